@@ -0,0 +1,372 @@
+// Copyright 2015 Jan Likar.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Seek, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use cargo::CargoResult;
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use tar::{Builder, EntryType, Header};
+use walkdir::WalkDir;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, DateTime, ZipWriter};
+
+// Modification time of every entry, the one cargo sets in `.crate` archives (2006-07-24).
+const MTIME: u64 = 1153704088;
+
+/// Format of the archives written instead of directories, see
+/// [`ClonerBuilder::with_archive`](crate::ClonerBuilder::with_archive).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    /// A gzipped tarball.
+    TarGz,
+    /// A zip archive.
+    Zip,
+    /// The pristine `.crate` archive, as published. Only available from remote registries.
+    Crate,
+}
+
+impl ArchiveFormat {
+    /// Extension of the archives, without the leading dot.
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::TarGz => "tar.gz",
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::Crate => "crate",
+        }
+    }
+
+    // path returns the path of the archive of a package which would otherwise be cloned into
+    // `dest_path`, by appending the extension unless it is already there.
+    pub(crate) fn path(self, dest_path: &Path) -> PathBuf {
+        let extension = format!(".{}", self.extension());
+        if dest_path.to_string_lossy().ends_with(&extension) {
+            return dest_path.to_owned();
+        }
+        let mut path = dest_path.as_os_str().to_owned();
+        path.push(extension);
+        PathBuf::from(path)
+    }
+}
+
+struct Entry {
+    /// Path in the archive, `/`-separated, without a trailing slash.
+    name: String,
+    kind: EntryKind,
+    mode: u32,
+}
+
+enum EntryKind {
+    Directory,
+    File(PathBuf),
+    Symlink(PathBuf),
+}
+
+/// Writes the contents of `dir` into a new archive at `to`, inside a `prefix` directory like in
+/// `.crate` archives.
+///
+/// Entries are sorted by path, and their times, owners and permissions are normalized, so the
+/// archive only depends on the contents of `dir`. Only regular files can be executable.
+pub(crate) fn write_archive(
+    dir: &Path,
+    prefix: &str,
+    to: &Path,
+    format: ArchiveFormat,
+) -> CargoResult<()> {
+    let entries = list_entries(dir, prefix)?;
+    let file =
+        File::create_new(to).with_context(|| format!("Unable to create `{}`.", to.display()))?;
+
+    let mut writer = BufWriter::new(file);
+    let written = match format {
+        ArchiveFormat::TarGz => write_tar_gz(&entries, &mut writer).map_err(Into::into),
+        ArchiveFormat::Zip => write_zip(&entries, &mut writer),
+        ArchiveFormat::Crate => unreachable!("`.crate` archives are copied, not written"),
+    }
+    .and_then(|()| Ok(writer.flush()?));
+
+    if written.is_err() {
+        // Do not leave a truncated archive behind.
+        drop(writer);
+        let _ = fs::remove_file(to);
+    }
+    written.with_context(|| format!("Unable to write `{}`.", to.display()))
+}
+
+// list_entries lists the contents of `dir` depth-first, sorted by file name, including `dir`
+// itself as `prefix`.
+fn list_entries(dir: &Path, prefix: &str) -> CargoResult<Vec<Entry>> {
+    let mut entries = vec![];
+    for entry in WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(dir)?;
+
+        let mut name = prefix.to_owned();
+        for component in relative.components() {
+            let component = component.as_os_str().to_str().with_context(|| {
+                format!(
+                    "Cannot archive `{}`, its path is not valid UTF-8.",
+                    entry.path().display()
+                )
+            })?;
+            name.push('/');
+            name.push_str(component);
+        }
+
+        let file_type = entry.file_type();
+        let (kind, mode) = if file_type.is_dir() {
+            (EntryKind::Directory, 0o755)
+        } else if file_type.is_symlink() {
+            (EntryKind::Symlink(fs::read_link(entry.path())?), 0o777)
+        } else if is_executable(&entry.metadata()?) {
+            (EntryKind::File(entry.path().to_owned()), 0o755)
+        } else {
+            (EntryKind::File(entry.path().to_owned()), 0o644)
+        };
+        entries.push(Entry { name, kind, mode });
+    }
+    Ok(entries)
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &fs::Metadata) -> bool {
+    false
+}
+
+fn write_tar_gz(entries: &[Entry], to: impl Write) -> io::Result<()> {
+    // The gzip header records neither a file name nor a time.
+    let mut builder = Builder::new(GzEncoder::new(to, Compression::default()));
+    for entry in entries {
+        let mut header = Header::new_gnu();
+        header.set_mtime(MTIME);
+        header.set_mode(entry.mode);
+        match &entry.kind {
+            EntryKind::Directory => {
+                header.set_entry_type(EntryType::Directory);
+                header.set_size(0);
+                builder.append_data(&mut header, &entry.name, io::empty())?;
+            }
+            EntryKind::File(path) => {
+                let file = File::open(path)?;
+                header.set_entry_type(EntryType::Regular);
+                header.set_size(file.metadata()?.len());
+                builder.append_data(&mut header, &entry.name, file)?;
+            }
+            EntryKind::Symlink(target) => {
+                header.set_entry_type(EntryType::Symlink);
+                header.set_size(0);
+                builder.append_link(&mut header, &entry.name, target)?;
+            }
+        }
+    }
+    builder.into_inner()?.finish()?;
+    Ok(())
+}
+
+// write_zip writes a zip archive with deflated files. Every entry is dated 1980-01-01 at
+// midnight, the earliest zip can record, and records its unix permissions.
+fn write_zip(entries: &[Entry], to: impl Write + Seek) -> CargoResult<()> {
+    let mut zip = ZipWriter::new(to);
+    for entry in entries {
+        let options = SimpleFileOptions::default()
+            .last_modified_time(DateTime::DEFAULT)
+            .unix_permissions(entry.mode);
+        match &entry.kind {
+            EntryKind::Directory => zip.add_directory(&entry.name, options)?,
+            EntryKind::File(path) => {
+                let mut file = File::open(path)
+                    .with_context(|| format!("Unable to read `{}`.", path.display()))?;
+                let large_file = file.metadata()?.len() >= u32::MAX.into();
+                zip.start_file(
+                    &entry.name,
+                    options
+                        .compression_method(CompressionMethod::Deflated)
+                        .large_file(large_file),
+                )?;
+                io::copy(&mut file, &mut zip)?;
+            }
+            EntryKind::Symlink(target) => {
+                let target = target.to_str().with_context(|| {
+                    format!(
+                        "Cannot archive `{}`, its target is not valid UTF-8.",
+                        entry.name
+                    )
+                })?;
+                zip.add_symlink(&entry.name, target, options)?;
+            }
+        }
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+
+    use filetime::FileTime;
+    use flate2::read::GzDecoder;
+    use tar::Archive;
+    use tempfile::tempdir;
+    use zip::ZipArchive;
+
+    fn package() -> tempfile::TempDir {
+        let dir = tempdir().unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("Cargo.toml"), "[package]\nname = \"foo\"\n").unwrap();
+        fs::write(
+            dir.path().join("src/lib.rs"),
+            "pub fn foo() {}\n".repeat(100),
+        )
+        .unwrap();
+        dir
+    }
+
+    fn write(dir: &Path, format: ArchiveFormat) -> Vec<u8> {
+        let out = tempdir().unwrap();
+        let path = out.path().join("foo").with_extension(format.extension());
+        write_archive(dir, "foo-0.1.0", &path, format).unwrap();
+        fs::read(path).unwrap()
+    }
+
+    fn touch(dir: &Path) {
+        let time = FileTime::from_unix_time(1_700_000_000, 0);
+        for path in ["Cargo.toml", "src/lib.rs", "src"] {
+            filetime::set_file_times(dir.join(path), time, time).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_path() {
+        assert_eq!(
+            PathBuf::from("out/foo.tar.gz"),
+            ArchiveFormat::TarGz.path(Path::new("out/foo"))
+        );
+        assert_eq!(
+            PathBuf::from("out/foo-1.0.0.zip"),
+            ArchiveFormat::Zip.path(Path::new("out/foo-1.0.0"))
+        );
+        assert_eq!(
+            PathBuf::from("foo.crate"),
+            ArchiveFormat::Crate.path(Path::new("foo.crate"))
+        );
+    }
+
+    #[test]
+    fn test_write_tar_gz() {
+        let dir = package();
+        let data = write(dir.path(), ArchiveFormat::TarGz);
+        touch(dir.path());
+        assert_eq!(data, write(dir.path(), ArchiveFormat::TarGz));
+
+        let mut archive = Archive::new(GzDecoder::new(data.as_slice()));
+        let entries = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let header = entry.header();
+                assert_eq!(MTIME, header.mtime().unwrap());
+                let path = entry.path().unwrap().display().to_string();
+                (path, header.mode().unwrap())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                ("foo-0.1.0".to_string(), 0o755),
+                ("foo-0.1.0/Cargo.toml".to_string(), 0o644),
+                ("foo-0.1.0/src".to_string(), 0o755),
+                ("foo-0.1.0/src/lib.rs".to_string(), 0o644),
+            ],
+            entries
+        );
+    }
+
+    #[test]
+    fn test_write_zip() {
+        let dir = package();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{PermissionsExt, symlink};
+            fs::write(dir.path().join("build.sh"), "#!/bin/sh\n").unwrap();
+            fs::set_permissions(
+                dir.path().join("build.sh"),
+                fs::Permissions::from_mode(0o700),
+            )
+            .unwrap();
+            symlink("src/lib.rs", dir.path().join("lib.rs")).unwrap();
+        }
+        let data = write(dir.path(), ArchiveFormat::Zip);
+        touch(dir.path());
+        assert_eq!(data, write(dir.path(), ArchiveFormat::Zip));
+
+        let mut archive = ZipArchive::new(io::Cursor::new(data)).unwrap();
+        let mut entries = vec![];
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i).unwrap();
+            assert_eq!(Some(DateTime::DEFAULT), file.last_modified());
+            let mut contents = String::new();
+            file.read_to_string(&mut contents).unwrap();
+            entries.push((file.name().to_owned(), file.unix_mode().unwrap(), contents));
+        }
+
+        let lib = "pub fn foo() {}\n".repeat(100);
+        let mut expected = vec![
+            ("foo-0.1.0/".to_owned(), 0o040755, String::new()),
+            (
+                "foo-0.1.0/Cargo.toml".to_owned(),
+                0o100644,
+                "[package]\nname = \"foo\"\n".to_owned(),
+            ),
+            ("foo-0.1.0/src/".to_owned(), 0o040755, String::new()),
+            ("foo-0.1.0/src/lib.rs".to_owned(), 0o100644, lib),
+        ];
+        if cfg!(unix) {
+            expected.insert(
+                2,
+                (
+                    "foo-0.1.0/build.sh".to_owned(),
+                    0o100755,
+                    "#!/bin/sh\n".to_owned(),
+                ),
+            );
+            expected.insert(
+                3,
+                (
+                    "foo-0.1.0/lib.rs".to_owned(),
+                    0o120777,
+                    "src/lib.rs".to_owned(),
+                ),
+            );
+        }
+        assert_eq!(expected, entries);
+    }
+
+    #[test]
+    fn test_write_archive_existing() {
+        let dir = package();
+        let out = tempdir().unwrap();
+        let path = out.path().join("foo.zip");
+        fs::write(&path, "").unwrap();
+
+        let err = write_archive(dir.path(), "foo-0.1.0", &path, ArchiveFormat::Zip).unwrap_err();
+        assert!(err.to_string().starts_with("Unable to create"));
+        assert!(path.exists());
+    }
+}
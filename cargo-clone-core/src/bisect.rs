@@ -0,0 +1,367 @@
+// Copyright 2015 Jan Likar.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::{env, fs};
+
+use anyhow::{Context, bail};
+use cargo::CargoResult;
+use toml_edit::{DocumentMut, InlineTable, Item, value};
+
+use crate::ClonedPackage;
+
+/// Outcome of testing a version while bisecting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BisectOutcome {
+    /// The version does not have the regression.
+    Good,
+    /// The version has the regression.
+    Bad,
+    /// The version cannot be tested.
+    Skip,
+}
+
+/// Result of [`Cloner::bisect`](crate::Cloner::bisect).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bisection {
+    /// Newest version without the regression.
+    /// `None` if every tested version has the regression.
+    pub last_good: Option<semver::Version>,
+    /// Oldest version with the regression.
+    /// `None` if no tested version has the regression.
+    pub first_bad: Option<semver::Version>,
+    /// Versions between `last_good` and `first_bad` which could not be tested.
+    /// If not empty, the regression may have been introduced by one of them.
+    pub skipped: Vec<semver::Version>,
+}
+
+// Indices of the last good item, the first bad item and the skipped items between them.
+pub(crate) type SearchResult = (Option<usize>, Option<usize>, Vec<usize>);
+
+// search finds the first bad item among `items`, sorted oldest first, with a binary search.
+// The versions before the oldest one are assumed to be good, and the ones after the newest
+// one to be bad.
+pub(crate) fn search<T>(
+    items: &[T],
+    mut test: impl FnMut(&T) -> CargoResult<BisectOutcome>,
+) -> CargoResult<SearchResult> {
+    // Known good below `lo` and known bad from `hi`, with `-1` and `len` as virtual bounds.
+    let mut lo: isize = -1;
+    let mut hi = items.len() as isize;
+    let mut skipped = vec![];
+
+    loop {
+        let mid = (lo + hi) / 2;
+        let candidate = (lo + 1..hi)
+            .filter(|i| !skipped.contains(i))
+            .min_by_key(|i| (i - mid).abs());
+        let Some(i) = candidate else {
+            break;
+        };
+
+        match test(&items[i as usize])? {
+            BisectOutcome::Good => lo = i,
+            BisectOutcome::Bad => hi = i,
+            BisectOutcome::Skip => skipped.push(i),
+        }
+    }
+
+    let last_good = (lo >= 0).then_some(lo as usize);
+    let first_bad = (hi < items.len() as isize).then_some(hi as usize);
+    let mut skipped: Vec<usize> = skipped
+        .into_iter()
+        .filter(|i| lo < *i && *i < hi)
+        .map(|i| i as usize)
+        .collect();
+    skipped.sort();
+    Ok((last_good, first_bad, skipped))
+}
+
+/// A command which tests a version while bisecting, like the commands of `git bisect run`.
+///
+/// Exit code 0 means the version is good and 125 that it cannot be tested.
+/// Any other code up to 127 means the version is bad, while higher codes stop the bisection.
+#[derive(Debug, Clone)]
+pub struct BisectCommand {
+    program: String,
+    args: Vec<String>,
+    project: Option<PathBuf>,
+}
+
+impl BisectCommand {
+    /// Creates a command, which runs in the directory of the cloned version.
+    pub fn new(command: &[String]) -> CargoResult<Self> {
+        let Some((program, args)) = command.split_first() else {
+            bail!("The command to test each version with is missing.");
+        };
+        Ok(Self {
+            program: program.clone(),
+            args: args.to_vec(),
+            project: None,
+        })
+    }
+
+    /// Run the command in the cargo project at `project` instead, with a temporary `[patch]`
+    /// pointing to the cloned version. `project` must be the root of the workspace.
+    /// The manifest and lock file of the project are restored after each run.
+    /// Versions which cargo does not use, e.g. because the requirement of the project excludes
+    /// them, are skipped without running the command.
+    ///
+    /// While the project is patched, they are backed up next to them with a `.cargo-clone-bak`
+    /// suffix. If the bisection is killed before they are restored, the next run fails until
+    /// they have been restored from the backups, or the backups removed.
+    pub fn with_project(self, project: impl Into<PathBuf>) -> Self {
+        Self {
+            project: Some(project.into()),
+            ..self
+        }
+    }
+
+    /// Tests a cloned version.
+    pub fn run(&self, package: &ClonedPackage) -> CargoResult<BisectOutcome> {
+        let mut command = Command::new(&self.program);
+        command.args(&self.args);
+
+        let _patch = match &self.project {
+            Some(project) => {
+                command.current_dir(project);
+                let patch = TemporaryPatch::apply(project, package)?;
+                if !patch.is_used(project, package)? {
+                    return Ok(BisectOutcome::Skip);
+                }
+                Some(patch)
+            }
+            None => {
+                command.current_dir(&package.path);
+                None
+            }
+        };
+
+        let status = command
+            .status()
+            .with_context(|| format!("Unable to run `{}`.", self.program))?;
+
+        match status.code() {
+            Some(0) => Ok(BisectOutcome::Good),
+            Some(125) => Ok(BisectOutcome::Skip),
+            Some(code) if code < 128 => Ok(BisectOutcome::Bad),
+            _ => bail!(
+                "Stopped bisecting, `{}` failed while testing {} {}: {}",
+                self.program,
+                package.name,
+                package.version,
+                status
+            ),
+        }
+    }
+}
+
+// Suffix of the backups of the manifest and lock file of a project while it is patched.
+const BACKUP_SUFFIX: &str = ".cargo-clone-bak";
+
+// TemporaryPatch adds a `[patch]` entry for a package to the manifest of a project, and
+// restores the manifest and the lock file when dropped.
+// Both files are backed up next to them first, so they can be restored even if the process is
+// killed before the patch is dropped.
+#[derive(Debug)]
+struct TemporaryPatch {
+    manifest_path: PathBuf,
+    lock_path: PathBuf,
+}
+
+impl TemporaryPatch {
+    fn apply(project: &Path, package: &ClonedPackage) -> CargoResult<Self> {
+        let manifest_path = project.join("Cargo.toml");
+        let lock_path = project.join("Cargo.lock");
+        let (manifest_backup, lock_backup) = (backup_path(&manifest_path), backup_path(&lock_path));
+        if manifest_backup.exists() || lock_backup.exists() {
+            bail!(
+                "A previous bisection was interrupted while `{}` was patched. Restore `Cargo.toml` and `Cargo.lock` from their `{BACKUP_SUFFIX}` backups, or remove the backups.",
+                project.display()
+            );
+        }
+
+        let manifest = fs::read_to_string(&manifest_path)
+            .with_context(|| format!("Unable to read `{}`.", manifest_path.display()))?;
+        let mut document: DocumentMut = manifest
+            .parse()
+            .with_context(|| format!("Unable to parse `{}`.", manifest_path.display()))?;
+        let registry = if package.source_id.is_crates_io() {
+            "crates-io".to_string()
+        } else {
+            package.source_id.url().to_string()
+        };
+        let mut patch = InlineTable::new();
+        patch.insert("path", package.path.display().to_string().into());
+        document["patch"][&registry][&package.name] = value(patch);
+
+        // The backup of the manifest is written last, since it marks the project as patched.
+        if lock_path.exists() {
+            fs::copy(&lock_path, &lock_backup)
+                .with_context(|| format!("Unable to write `{}`.", lock_backup.display()))?;
+        }
+        fs::write(&manifest_backup, &manifest)
+            .with_context(|| format!("Unable to write `{}`.", manifest_backup.display()))?;
+
+        let patched = Self {
+            manifest_path,
+            lock_path,
+        };
+        fs::write(&patched.manifest_path, document.to_string())
+            .with_context(|| format!("Unable to write `{}`.", patched.manifest_path.display()))?;
+        Ok(patched)
+    }
+
+    // is_used resolves the dependencies of the patched project with `cargo metadata`, and
+    // returns whether the lock file then has the cloned package. Cargo only warns about a patch
+    // which matches no requirement, and resolves the package from its registry instead.
+    fn is_used(&self, project: &Path, package: &ClonedPackage) -> CargoResult<bool> {
+        let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+        // Cargo's warnings, like the one about an unused patch, are left on stderr.
+        let status = Command::new(cargo)
+            .args(["metadata", "--format-version", "1", "--manifest-path"])
+            .arg(&self.manifest_path)
+            .current_dir(project)
+            .stdout(Stdio::null())
+            .status()
+            .context("Unable to run `cargo metadata`.")?;
+        if !status.success() {
+            return Ok(false);
+        }
+
+        let lock = fs::read_to_string(&self.lock_path)
+            .with_context(|| format!("Unable to read `{}`.", self.lock_path.display()))?;
+        let document: DocumentMut = lock
+            .parse()
+            .with_context(|| format!("Unable to parse `{}`.", self.lock_path.display()))?;
+        let version = package.version.to_string();
+        let packages = document.get("package").and_then(Item::as_array_of_tables);
+        // Patched packages are path dependencies, which have no source in the lock file.
+        Ok(packages.is_some_and(|packages| {
+            packages.iter().any(|p| {
+                p.get("name").and_then(Item::as_str) == Some(package.name.as_str())
+                    && p.get("version").and_then(Item::as_str) == Some(version.as_str())
+                    && !p.contains_key("source")
+            })
+        }))
+    }
+}
+
+impl Drop for TemporaryPatch {
+    fn drop(&mut self) {
+        let lock_backup = backup_path(&self.lock_path);
+        let _ = if lock_backup.exists() {
+            fs::rename(&lock_backup, &self.lock_path)
+        } else {
+            fs::remove_file(&self.lock_path)
+        };
+        // If this fails, the backup is left for the next bisection to report.
+        let _ = fs::rename(backup_path(&self.manifest_path), &self.manifest_path);
+    }
+}
+
+// backup_path returns the path of the backup of `path` while a project is patched.
+fn backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(BACKUP_SUFFIX);
+    PathBuf::from(backup)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cargo::core::SourceId;
+    use cargo::util::GlobalContext;
+    use tempfile::tempdir;
+
+    // bisect runs `search` on versions 0 to `count - 1`, where `first_bad` introduced the
+    // regression, and returns the result and the tested versions.
+    fn bisect(count: usize, first_bad: usize, skip: &[usize]) -> (SearchResult, Vec<usize>) {
+        let versions: Vec<usize> = (0..count).collect();
+        let mut tested = vec![];
+        let result = search(&versions, |&v| {
+            tested.push(v);
+            Ok(if skip.contains(&v) {
+                BisectOutcome::Skip
+            } else if v >= first_bad {
+                BisectOutcome::Bad
+            } else {
+                BisectOutcome::Good
+            })
+        })
+        .unwrap();
+        (result, tested)
+    }
+
+    #[test]
+    fn test_search() {
+        let (result, tested) = bisect(100, 42, &[]);
+
+        assert_eq!((Some(41), Some(42), vec![]), result);
+        assert!(tested.len() <= 7);
+    }
+
+    #[test]
+    fn test_search_all_good_or_bad() {
+        assert_eq!((Some(9), None, vec![]), bisect(10, 10, &[]).0);
+        assert_eq!((None, Some(0), vec![]), bisect(10, 0, &[]).0);
+    }
+
+    #[test]
+    fn test_search_skip() {
+        assert_eq!((Some(4), Some(5), vec![]), bisect(10, 5, &[3]).0);
+        assert_eq!((Some(3), Some(6), vec![4, 5]), bisect(10, 5, &[4, 5]).0);
+    }
+
+    #[test]
+    fn test_temporary_patch() {
+        let project = tempdir().unwrap();
+        let manifest =
+            "[package]\nname = \"bar\"\n\n[patch.crates-io]\nbaz = { path = \"../baz\" }\n";
+        fs::write(project.path().join("Cargo.toml"), manifest).unwrap();
+
+        let context = GlobalContext::default().unwrap();
+        let package = ClonedPackage {
+            name: "foo".to_string(),
+            version: semver::Version::new(1, 2, 0),
+            source_id: SourceId::crates_io(&context).unwrap(),
+            replaced_source_id: SourceId::crates_io(&context).unwrap(),
+            checksum: None,
+            path: PathBuf::from("/tmp/foo-1.2.0"),
+            skipped_files: 0,
+            skipped_bytes: 0,
+        };
+
+        let patch = TemporaryPatch::apply(project.path(), &package).unwrap();
+        let patched = fs::read_to_string(project.path().join("Cargo.toml")).unwrap();
+        let backup = project.path().join("Cargo.toml.cargo-clone-bak");
+        assert_eq!(manifest, fs::read_to_string(&backup).unwrap());
+        fs::write(project.path().join("Cargo.lock"), "").unwrap();
+
+        // The backup of an interrupted bisection is not overwritten.
+        let err = TemporaryPatch::apply(project.path(), &package).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("A previous bisection was interrupted")
+        );
+        drop(patch);
+
+        assert_eq!(
+            "[package]\nname = \"bar\"\n\n[patch.crates-io]\nbaz = { path = \"../baz\" }\nfoo = { path = \"/tmp/foo-1.2.0\" }\n",
+            patched
+        );
+        assert_eq!(
+            manifest,
+            fs::read_to_string(project.path().join("Cargo.toml")).unwrap()
+        );
+        assert!(!project.path().join("Cargo.lock").exists());
+        assert!(!backup.exists());
+    }
+}
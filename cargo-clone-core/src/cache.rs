@@ -0,0 +1,95 @@
+// Copyright 2015 Jan Likar.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::fs;
+use std::path::PathBuf;
+
+use cargo::core::{PackageId, SourceId};
+use cargo::util::GlobalContext;
+use cargo::util::hex::short_hash;
+
+// registry_dir_name returns the name of the directory in which cargo stores the files of a remote
+// registry. It mirrors `short_name` in cargo's registry source.
+fn registry_dir_name(srcid: SourceId) -> String {
+    let ident = srcid.url().host_str().unwrap_or("");
+    format!("{}-{}", ident, short_hash(&srcid))
+}
+
+/// Path of the `.crate` file of `pkg_id` in cargo's download cache.
+///
+/// `srcid` is the remote registry which serves the package, after source replacement.
+/// Returns `None` for other kinds of sources.
+pub(crate) fn cached_crate_path(
+    context: &GlobalContext,
+    srcid: SourceId,
+    pkg_id: PackageId,
+) -> Option<PathBuf> {
+    if !srcid.is_remote_registry() {
+        return None;
+    }
+
+    let path = context
+        .registry_cache_path()
+        .join(registry_dir_name(srcid))
+        .into_path_unlocked()
+        .join(pkg_id.tarball_name());
+    Some(path)
+}
+
+/// Path of the directory in which cargo has extracted the sources of `pkg_id`.
+///
+/// `srcid` is the remote registry which serves the package, after source replacement.
+/// Returns `None` if the package has not been completely extracted, or for other kinds of sources.
+pub(crate) fn extracted_crate_path(
+    context: &GlobalContext,
+    srcid: SourceId,
+    pkg_id: PackageId,
+) -> Option<PathBuf> {
+    if !srcid.is_remote_registry() {
+        return None;
+    }
+
+    let path = context
+        .registry_source_path()
+        .join(registry_dir_name(srcid))
+        .into_path_unlocked()
+        .join(format!("{}-{}", pkg_id.name(), pkg_id.version()));
+
+    // Cargo writes this marker after the package has been unpacked successfully.
+    // Older versions of cargo wrote other contents, which cargo itself no longer trusts.
+    let ok = fs::read_to_string(path.join(".cargo-ok")).ok()?;
+    (ok == r#"{"v":1}"#).then_some(path)
+}
+
+/// Whether the package can be cloned without downloading it.
+pub(crate) fn is_crate_downloaded(
+    context: &GlobalContext,
+    srcid: SourceId,
+    pkg_id: PackageId,
+) -> bool {
+    match cached_crate_path(context, srcid, pkg_id) {
+        Some(path) => {
+            fs::metadata(path).is_ok_and(|m| m.len() > 0)
+                || extracted_crate_path(context, srcid, pkg_id).is_some()
+        }
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_dir_name() {
+        let context = GlobalContext::default().unwrap();
+        let srcid = SourceId::crates_io_maybe_sparse_http(&context).unwrap();
+
+        assert_eq!("index.crates.io-1949cf8c6b5b557f", registry_dir_name(srcid));
+    }
+}
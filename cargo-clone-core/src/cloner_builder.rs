@@ -6,13 +6,19 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::{env, path::PathBuf};
+use std::env;
+use std::path::{Component, PathBuf};
 
-use anyhow::Context;
+use anyhow::{Context, bail};
 use cargo::CargoResult;
 use cargo::util::context::GlobalContext;
 
-use crate::{Cloner, ClonerSource};
+use crate::filter::FileFilter;
+use crate::source::CargoSource;
+use crate::{
+    ArchiveFormat, CloneLock, Cloner, ClonerSource, CopyOptions, FilterPreset, SourceReplacement,
+    VersionSelection, render_dir_template,
+};
 
 /// Builder for [`Cloner`].
 #[derive(Debug, Default)]
@@ -20,7 +26,18 @@ pub struct ClonerBuilder {
     context: Option<GlobalContext>,
     directory: Option<PathBuf>,
     source: ClonerSource,
+    source_replacement: SourceReplacement,
     use_git: bool,
+    history: bool,
+    git_init: bool,
+    all_matching: bool,
+    archive: Option<ArchiveFormat>,
+    dir_template: Option<String>,
+    lock: Option<CloneLock>,
+    version_selection: VersionSelection,
+    copy_options: CopyOptions,
+    include: Vec<String>,
+    exclude: Vec<String>,
 }
 
 impl ClonerBuilder {
@@ -52,13 +69,222 @@ impl ClonerBuilder {
         Self { source, ..self }
     }
 
+    /// Control whether source replacement from cargo's configuration is followed.
+    pub fn with_source_replacement(self, source_replacement: SourceReplacement) -> Self {
+        Self {
+            source_replacement,
+            ..self
+        }
+    }
+
     /// Clone the git repository present in the manifest metadata.
     pub fn with_git(self, use_git: bool) -> Self {
         Self { use_git, ..self }
     }
 
+    /// Clone every version of a package matching the requested version, instead of only the
+    /// latest one, into a git repository with one commit per version, oldest first.
+    ///
+    /// Each commit is tagged `v{version}` and dated with the publish date of the version,
+    /// when the registry records it. Yanked versions are skipped.
+    pub fn with_history(self, history: bool) -> Self {
+        Self { history, ..self }
+    }
+
+    /// Initialize a git repository in the destination and commit the pristine sources,
+    /// with a message recording the name, version and source of the package.
+    ///
+    /// Not supported with [`ClonerBuilder::with_link`], since the committed files would be
+    /// shared with cargo's cache.
+    pub fn with_git_init(self, git_init: bool) -> Self {
+        Self { git_init, ..self }
+    }
+
+    /// Select the version to clone among the versions matching the requested one with
+    /// `version_selection`, instead of selecting the newest one.
+    /// The same strategy applies to every cloned crate.
+    ///
+    /// Selecting the oldest version is not supported when cloning every matching version or
+    /// a history, which clone every matching version anyway.
+    pub fn with_version_selection(self, version_selection: VersionSelection) -> Self {
+        Self {
+            version_selection,
+            ..self
+        }
+    }
+
+    /// Clone every version of a package matching the requested version, instead of only the
+    /// latest one, into `name-version` subdirectories. Yanked versions are skipped.
+    pub fn with_all_matching(self, all_matching: bool) -> Self {
+        Self {
+            all_matching,
+            ..self
+        }
+    }
+
+    /// Write each package as a single archive in `format`, instead of a directory.
+    ///
+    /// The archive is written at the path the package would be cloned into, with the extension
+    /// of `format` appended unless it is already there. Its entries are sorted and their times,
+    /// owners and permissions are normalized, so the same package gives the same archive.
+    /// Pristine `.crate` archives are copied as published, and cannot be filtered.
+    ///
+    /// Not supported when cloning from git repositories, as a history, or with
+    /// [`ClonerBuilder::with_git_init`].
+    pub fn with_archive(self, format: ArchiveFormat) -> Self {
+        Self {
+            archive: Some(format),
+            ..self
+        }
+    }
+
+    /// Name the subdirectories the crates are cloned into after `template`, instead of the
+    /// crate name, or `{name}-{version}` when cloning every matching version.
+    ///
+    /// `{name}` and `{version}` are replaced by the name and the selected version of the crate.
+    /// It does not apply to [`Cloner::clone_in_dir`], nor to crates with a destination.
+    pub fn with_dir_template(self, template: impl Into<String>) -> Self {
+        Self {
+            dir_template: Some(template.into()),
+            ..self
+        }
+    }
+
+    /// Clone the versions recorded in `lock` instead of selecting a version, and fail if their
+    /// checksum changed since they were locked. Every crate must be in the lock.
+    ///
+    /// Not supported when cloning every matching version or a history.
+    pub fn with_lock(self, lock: CloneLock) -> Self {
+        Self {
+            lock: Some(lock),
+            ..self
+        }
+    }
+
+    /// Hard link the files of the package instead of copying them, where possible.
+    ///
+    /// This saves disk space, but the cloned files share their contents with cargo's cache,
+    /// so they should not be modified in place.
+    pub fn with_link(mut self, link: bool) -> Self {
+        self.copy_options.link = link;
+        self
+    }
+
+    /// Copy the targets of symbolic links in the package, instead of recreating the links.
+    pub fn with_follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.copy_options.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    /// Skip files and directories of the package which cannot be read, instead of failing.
+    /// A warning is printed for each skipped entry.
+    pub fn with_skip_unreadable(mut self, skip_unreadable: bool) -> Self {
+        self.copy_options.skip_unreadable = skip_unreadable;
+        self
+    }
+
+    /// Only clone the files of the package matching one of these glob patterns.
+    ///
+    /// Patterns are matched against paths relative to the package root. A pattern matching a
+    /// directory selects everything inside it. `*` does not match `/`, use `**` for that.
+    pub fn with_include<I, S>(mut self, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.include.extend(patterns.into_iter().map(Into::into));
+        self
+    }
+
+    /// Do not clone the files of the package matching one of these glob patterns.
+    ///
+    /// Patterns are matched like in [`ClonerBuilder::with_include`].
+    /// Exclude patterns take precedence over include patterns.
+    pub fn with_exclude<I, S>(mut self, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.exclude.extend(patterns.into_iter().map(Into::into));
+        self
+    }
+
+    /// Only clone this directory or file of the package, e.g. `src/codec`, relative to its root.
+    ///
+    /// The contents of the directory, or the file, are cloned into the destination itself.
+    /// File filters still match paths relative to the package root. Cloning fails if the
+    /// subpath does not exist in the selected version.
+    pub fn with_subpath(mut self, subpath: impl Into<PathBuf>) -> Self {
+        self.copy_options.subpath = Some(subpath.into());
+        self
+    }
+
+    /// Add the patterns of a [`FilterPreset`].
+    pub fn with_preset(self, preset: FilterPreset) -> Self {
+        self.with_include(preset.includes().iter().copied())
+            .with_exclude(preset.excludes().iter().copied())
+    }
+
     /// Build the [`Cloner`].
     pub fn build(self) -> CargoResult<Cloner> {
+        if self.use_git && self.history {
+            bail!("Cannot clone the history of a package from its git repository.");
+        }
+        if self.all_matching && (self.use_git || self.history) {
+            bail!("Cannot clone every matching version from a git repository or as a history.");
+        }
+        if self.use_git && self.git_init {
+            bail!(
+                "Cannot initialize a git repository when cloning from the package's git repository."
+            );
+        }
+        if self.git_init && self.copy_options.link {
+            bail!(
+                "Cannot initialize a git repository in clones whose files are hard links into cargo's cache."
+            );
+        }
+
+        if self.archive.is_some() && (self.use_git || self.history || self.git_init) {
+            bail!("Cannot write archives of git repositories or histories.");
+        }
+        if self.archive == Some(ArchiveFormat::Crate)
+            && !(self.include.is_empty() && self.exclude.is_empty())
+        {
+            bail!("Cannot filter the files of pristine `.crate` archives.");
+        }
+
+        if self.version_selection == VersionSelection::Oldest && (self.all_matching || self.history)
+        {
+            bail!(
+                "Cannot select the oldest version when cloning every matching version or a history."
+            );
+        }
+        if self.lock.is_some() && (self.all_matching || self.history) {
+            bail!("Cannot clone locked versions when cloning every matching version or a history.");
+        }
+        if let Some(template) = &self.dir_template {
+            render_dir_template(template, "name", "1.0.0")?;
+        }
+
+        let mut copy_options = self.copy_options;
+        copy_options.filter = FileFilter::new(&self.include, &self.exclude)?;
+        if let Some(subpath) = &copy_options.subpath {
+            if self.use_git || self.archive == Some(ArchiveFormat::Crate) {
+                bail!("Cannot clone a subpath of git repositories or pristine `.crate` archives.");
+            }
+            let is_inside = |c: Component| matches!(c, Component::Normal(_) | Component::CurDir);
+            if !subpath.components().all(is_inside)
+                || !subpath
+                    .components()
+                    .any(|c| matches!(c, Component::Normal(_)))
+            {
+                bail!(
+                    "Invalid subpath `{}`, it must be a relative path inside the package.",
+                    subpath.display()
+                );
+            }
+        }
+
         let context = match self.context {
             Some(context) => context,
             None => GlobalContext::default().context("Unable to get cargo context.")?,
@@ -69,17 +295,29 @@ impl ClonerBuilder {
             None => env::current_dir().context("Unable to get current directory.")?,
         };
 
-        let srcid = self
-            .source
-            .cargo_source
-            .to_source_id(&context)
-            .context("can't determine the source id")?;
+        // `.crate` archives are only fetched when a crate is cloned.
+        if !matches!(self.source.cargo_source, CargoSource::CrateFile { .. }) {
+            self.source
+                .cargo_source
+                .to_source_id(&context)
+                .context("can't determine the source id")?;
+        }
 
         Ok(Cloner {
             context,
             directory,
-            srcid,
+            source: self.source,
+            source_replacement: self.source_replacement,
             use_git: self.use_git,
+            history: self.history,
+            git_init: self.git_init,
+            all_matching: self.all_matching,
+            archive: self.archive,
+            dir_template: self.dir_template,
+            lock: self.lock,
+            version_selection: self.version_selection,
+            copy_options,
+            crate_files: Default::default(),
         })
     }
 }
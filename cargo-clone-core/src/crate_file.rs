@@ -0,0 +1,248 @@
+// Copyright 2015 Jan Likar.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, bail};
+use cargo::CargoResult;
+use cargo::util::GlobalContext;
+use cargo::util::network::http::http_handle;
+use cargo_util::Sha256;
+use flate2::read::GzDecoder;
+use tar::{Archive, EntryType};
+use tempfile::TempDir;
+use url::Url;
+
+use crate::IGNORED_FILES;
+
+/// A `.crate` archive unpacked into a temporary directory.
+#[derive(Debug)]
+pub(crate) struct UnpackedCrate {
+    /// Keeps the temporary directory alive.
+    _dir: TempDir,
+    /// Root of the package inside the temporary directory.
+    pub(crate) root: PathBuf,
+}
+
+/// Fetches the `.crate` archive at `location`, verifies its checksum and unpacks it.
+///
+/// `location` is either a path or a `file://`, `http://` or `https://` URL.
+pub(crate) fn unpack_crate_file(
+    context: &GlobalContext,
+    location: &str,
+    sha256: Option<&str>,
+) -> CargoResult<UnpackedCrate> {
+    let data = fetch(context, location)?;
+
+    if let Some(expected) = sha256 {
+        verify_checksum(&data, expected).with_context(|| format!("Cannot use `{location}`."))?;
+    }
+
+    unpack_crate_data(&data, location)
+}
+
+/// Unpacks the `.crate` archive `data`, read from `location`, into a temporary directory.
+pub(crate) fn unpack_crate_data(data: &[u8], location: &str) -> CargoResult<UnpackedCrate> {
+    let dir = TempDir::new().context("Unable to create a temporary directory.")?;
+    let root =
+        unpack(data, dir.path()).with_context(|| format!("Unable to unpack `{location}`."))?;
+
+    Ok(UnpackedCrate { _dir: dir, root })
+}
+
+fn fetch(context: &GlobalContext, location: &str) -> CargoResult<Vec<u8>> {
+    let path = match Url::parse(location) {
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {
+            context.shell().note(format!("Downloading {location}"))?;
+            return download(context, &url);
+        }
+        Ok(url) if url.scheme() == "file" => url
+            .to_file_path()
+            .map_err(|_| anyhow::format_err!("Invalid file URL: `{location}`."))?,
+        _ => context.cwd().join(location),
+    };
+
+    fs::read(&path).with_context(|| format!("Unable to read `{}`.", path.display()))
+}
+
+fn download(context: &GlobalContext, url: &Url) -> CargoResult<Vec<u8>> {
+    let mut handle = http_handle(context)?;
+    handle.get(true)?;
+    handle.url(url.as_str())?;
+    handle.follow_location(true)?;
+
+    let mut data = Vec::new();
+    {
+        let mut transfer = handle.transfer();
+        transfer.write_function(|buf| {
+            data.extend_from_slice(buf);
+            Ok(buf.len())
+        })?;
+        transfer
+            .perform()
+            .with_context(|| format!("Failed to download `{url}`."))?;
+    }
+
+    let code = handle.response_code()?;
+    if code != 200 {
+        bail!("Failed to download `{url}`: server responded with status {code}.");
+    }
+
+    Ok(data)
+}
+
+pub(crate) fn verify_checksum(data: &[u8], expected: &str) -> CargoResult<()> {
+    let actual = Sha256::new().update(data).finish_hex();
+    if !actual.eq_ignore_ascii_case(expected) {
+        bail!("Checksum mismatch: expected sha256 `{expected}`, got `{actual}`.");
+    }
+    Ok(())
+}
+
+// unpack extracts a gzipped `.crate` archive into `to` and returns the path of the package root.
+// Every entry must live in the same top-level `name-version` directory. Entries that would
+// escape it, links and other special files are rejected.
+fn unpack(data: &[u8], to: &Path) -> CargoResult<PathBuf> {
+    let mut archive = Archive::new(GzDecoder::new(data));
+    let mut prefix: Option<PathBuf> = None;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_path = entry.path()?.into_owned();
+
+        let mut components = entry_path.components();
+        let top = match components.next() {
+            Some(Component::Normal(top)) => PathBuf::from(top),
+            _ => bail!("Invalid path in archive: `{}`.", entry_path.display()),
+        };
+        let relative = components.as_path();
+        if relative
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            bail!("Invalid path in archive: `{}`.", entry_path.display());
+        }
+
+        match &prefix {
+            Some(prefix) if *prefix != top => bail!(
+                "Archive contains more than one top-level directory: `{}` and `{}`.",
+                prefix.display(),
+                top.display()
+            ),
+            Some(_) => {}
+            None => {
+                fs::create_dir(to.join(&top))?;
+                prefix = Some(top.clone());
+            }
+        }
+
+        if relative.as_os_str().is_empty() {
+            continue;
+        }
+        if IGNORED_FILES.iter().any(|f| relative == Path::new(f)) {
+            continue;
+        }
+
+        let dest_path = to.join(&top).join(relative);
+        match entry.header().entry_type() {
+            EntryType::Directory => fs::create_dir_all(&dest_path)?,
+            EntryType::Regular => {
+                if let Some(parent) = dest_path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                entry
+                    .unpack(&dest_path)
+                    .with_context(|| format!("Unable to unpack `{}`.", entry_path.display()))?;
+            }
+            other => bail!(
+                "Unsupported entry type {:?} in archive: `{}`.",
+                other,
+                entry_path.display()
+            ),
+        }
+    }
+
+    match prefix {
+        Some(prefix) => Ok(to.join(prefix)),
+        None => bail!("Archive is empty."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use tar::{Builder, Header};
+    use tempfile::tempdir;
+
+    fn archive(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (path, data) in entries {
+            let mut header = Header::new_gnu();
+            // Set the name directly, so invalid paths can be written as well.
+            header.as_gnu_mut().unwrap().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_entry_type(EntryType::Regular);
+            header.set_cksum();
+            builder.append(&header, *data).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn test_unpack() {
+        let data = archive(&[
+            ("foo-0.1.0/Cargo.toml", b"[package]"),
+            ("foo-0.1.0/src/lib.rs", b""),
+            ("foo-0.1.0/.cargo-ok", b""),
+        ]);
+        let to = tempdir().unwrap();
+
+        let root = unpack(&data, to.path()).unwrap();
+
+        assert_eq!(root, to.path().join("foo-0.1.0"));
+        assert_eq!(fs::read(root.join("Cargo.toml")).unwrap(), b"[package]");
+        assert!(root.join("src/lib.rs").exists());
+        assert!(!root.join(".cargo-ok").exists());
+    }
+
+    #[test]
+    fn test_unpack_path_traversal() {
+        let data = archive(&[("foo-0.1.0/Cargo.toml", b""), ("foo-0.1.0/../../evil", b"")]);
+        let to = tempdir().unwrap();
+
+        let err = unpack(&data, to.path()).unwrap_err();
+
+        assert_eq!(
+            "Invalid path in archive: `foo-0.1.0/../../evil`.",
+            err.to_string()
+        );
+        assert!(!to.path().join("evil").exists());
+    }
+
+    #[test]
+    fn test_unpack_multiple_roots() {
+        let data = archive(&[("foo-0.1.0/Cargo.toml", b""), ("bar-0.1.0/Cargo.toml", b"")]);
+        let to = tempdir().unwrap();
+
+        assert!(unpack(&data, to.path()).is_err());
+    }
+
+    #[test]
+    fn test_verify_checksum() {
+        let sha = "2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae";
+
+        verify_checksum(b"foo", sha).unwrap();
+        verify_checksum(b"foo", &sha.to_uppercase()).unwrap();
+        assert!(verify_checksum(b"bar", sha).is_err());
+    }
+}
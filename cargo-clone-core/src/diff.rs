@@ -0,0 +1,512 @@
+// Copyright 2015 Jan Likar.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::Context;
+use cargo::CargoResult;
+use imara_diff::intern::InternedInput;
+use imara_diff::sources::lines_with_terminator;
+use imara_diff::{Algorithm, diff};
+use jiff::Timestamp;
+use jiff::fmt::rfc2822::DateTimePrinter;
+use walkdir::WalkDir;
+
+use crate::FileFilter;
+use crate::sync::RECORD_FILE;
+
+// Files which differ between any two packages or clones without being part of their sources.
+const DIFF_IGNORED_FILES: &[&str] = &[".cargo-ok", ".cargo_vcs_info.json", RECORD_FILE];
+
+// Directories of a local checkout which are never part of a package.
+const DIFF_IGNORED_DIRS: &[&str] = &[".git", "target"];
+
+// Number of unchanged lines shown around each change.
+const CONTEXT_LINES: u32 = 3;
+
+// Width of the widest bar of `--stat`.
+const STAT_WIDTH: usize = 50;
+
+/// How a file changed between two versions of a package.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileChange {
+    /// The file only exists in the new version.
+    Added,
+    /// The file only exists in the old version.
+    Removed,
+    /// The contents of the file differ.
+    Modified,
+}
+
+/// Changes to a single file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileDiff {
+    /// Path of the file, relative to the package root and separated by `/`.
+    pub path: String,
+    /// How the file changed.
+    pub change: FileChange,
+    /// Number of added lines.
+    pub insertions: usize,
+    /// Number of removed lines.
+    pub deletions: usize,
+    /// Whether the file is binary, in which case no lines are compared.
+    pub binary: bool,
+    /// Hunks of the unified diff.
+    hunks: String,
+}
+
+/// Changes between two versions of a package.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackageDiff {
+    /// Name of the package.
+    pub name: String,
+    /// Version of the package the changes are relative to.
+    pub version: semver::Version,
+    /// Prefix of the paths of the old files, e.g. `foo-1.2.0`.
+    pub old: String,
+    /// Prefix of the paths of the new files, e.g. `foo-1.3.0`.
+    pub new: String,
+    /// Changed files, sorted by path.
+    pub files: Vec<FileDiff>,
+}
+
+impl PackageDiff {
+    /// Writes the changes as a unified diff, which can be applied with `patch -p1`.
+    pub fn write_unified(&self, out: &mut dyn Write) -> io::Result<()> {
+        for file in &self.files {
+            let old = match file.change {
+                FileChange::Added => "/dev/null".to_string(),
+                _ => format!("{}/{}", self.old, file.path),
+            };
+            let new = match file.change {
+                FileChange::Removed => "/dev/null".to_string(),
+                _ => format!("{}/{}", self.new, file.path),
+            };
+
+            if file.binary {
+                writeln!(out, "Binary files {old} and {new} differ")?;
+            } else {
+                writeln!(out, "--- {old}")?;
+                writeln!(out, "+++ {new}")?;
+                out.write_all(file.hunks.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the changes as an email in the format of `git format-patch`,
+    /// which can be applied with `git am`. Binary files cannot be applied.
+    pub fn write_patch(
+        &self,
+        out: &mut dyn Write,
+        author: &str,
+        date: SystemTime,
+        subject: &str,
+    ) -> io::Result<()> {
+        let date = Timestamp::try_from(date)
+            .and_then(|date| DateTimePrinter::new().timestamp_to_string(&date))
+            .map_err(io::Error::other)?;
+
+        // The commit hash and date of the first line are fixed, so mail programs can recognize the patch.
+        writeln!(
+            out,
+            "From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001"
+        )?;
+        writeln!(out, "From: {author}")?;
+        writeln!(out, "Date: {date}")?;
+        writeln!(out, "Subject: [PATCH] {subject}")?;
+        writeln!(out)?;
+        writeln!(out, "---")?;
+        self.write_stat(out)?;
+        writeln!(out)?;
+        self.write_unified(out)
+    }
+
+    /// Writes a summary of the changed files, in the style of `git diff --stat`.
+    pub fn write_stat(&self, out: &mut dyn Write) -> io::Result<()> {
+        let path_width = self.files.iter().map(|f| f.path.len()).max().unwrap_or(0);
+        let max_changes = self
+            .files
+            .iter()
+            .map(|f| f.insertions + f.deletions)
+            .max()
+            .unwrap_or(0);
+        let count_width = max_changes.to_string().len();
+
+        for file in &self.files {
+            if file.binary {
+                writeln!(out, " {:path_width$} | Bin", file.path)?;
+                continue;
+            }
+
+            let changes = file.insertions + file.deletions;
+            let (plus, minus) = if max_changes > STAT_WIDTH {
+                // Scale the bars, but keep at least one character for any change.
+                let scale = |n: usize| match n {
+                    0 => 0,
+                    n => (n * STAT_WIDTH / max_changes).max(1),
+                };
+                (scale(file.insertions), scale(file.deletions))
+            } else {
+                (file.insertions, file.deletions)
+            };
+            writeln!(
+                out,
+                " {:path_width$} | {:>count_width$} {}{}",
+                file.path,
+                changes,
+                "+".repeat(plus),
+                "-".repeat(minus)
+            )?;
+        }
+
+        let insertions: usize = self.files.iter().map(|f| f.insertions).sum();
+        let deletions: usize = self.files.iter().map(|f| f.deletions).sum();
+        let mut summary = format!(
+            " {} {} changed",
+            self.files.len(),
+            plural(self.files.len(), "file", "files")
+        );
+        if insertions > 0 || deletions == 0 {
+            let word = plural(insertions, "insertion", "insertions");
+            write!(summary, ", {insertions} {word}(+)").unwrap();
+        }
+        if deletions > 0 || insertions == 0 {
+            let word = plural(deletions, "deletion", "deletions");
+            write!(summary, ", {deletions} {word}(-)").unwrap();
+        }
+        writeln!(out, "{summary}")
+    }
+}
+
+fn plural<'a>(n: usize, one: &'a str, many: &'a str) -> &'a str {
+    if n == 1 { one } else { many }
+}
+
+// diff_directories compares the files of two packages, which are selected by `filter`.
+pub(crate) fn diff_directories(
+    old: &Path,
+    new: &Path,
+    filter: &FileFilter,
+) -> CargoResult<Vec<FileDiff>> {
+    let mut paths: BTreeMap<String, (Option<PathBuf>, Option<PathBuf>)> = BTreeMap::new();
+    for (path, file) in list_files(old, filter)? {
+        paths.entry(path).or_default().0 = Some(file);
+    }
+    for (path, file) in list_files(new, filter)? {
+        paths.entry(path).or_default().1 = Some(file);
+    }
+
+    let mut files = vec![];
+    for (path, (old, new)) in paths {
+        let old_data = old.as_deref().map(read_file).transpose()?;
+        let new_data = new.as_deref().map(read_file).transpose()?;
+        if old_data == new_data {
+            continue;
+        }
+
+        let change = match (&old_data, &new_data) {
+            (None, _) => FileChange::Added,
+            (_, None) => FileChange::Removed,
+            _ => FileChange::Modified,
+        };
+        let old_data = old_data.unwrap_or_default();
+        let new_data = new_data.unwrap_or_default();
+
+        let file = match (
+            std::str::from_utf8(&old_data),
+            std::str::from_utf8(&new_data),
+        ) {
+            (Ok(old_text), Ok(new_text))
+                if !old_text.contains('\0') && !new_text.contains('\0') =>
+            {
+                let (hunks, insertions, deletions) = diff_text(old_text, new_text);
+                FileDiff {
+                    path,
+                    change,
+                    insertions,
+                    deletions,
+                    binary: false,
+                    hunks,
+                }
+            }
+            _ => FileDiff {
+                path,
+                change,
+                insertions: 0,
+                deletions: 0,
+                binary: true,
+                hunks: String::new(),
+            },
+        };
+        files.push(file);
+    }
+
+    Ok(files)
+}
+
+// list_files returns the files and symbolic links inside `root`, keyed by their relative path.
+pub(crate) fn list_files(root: &Path, filter: &FileFilter) -> CargoResult<Vec<(String, PathBuf)>> {
+    let is_ignored =
+        |relative: &Path, ignored: &[&str]| ignored.iter().any(|f| relative == Path::new(f));
+
+    let mut files = vec![];
+    let walker = WalkDir::new(root)
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|entry| {
+            let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+            let is_ignored_dir =
+                entry.file_type().is_dir() && is_ignored(relative, DIFF_IGNORED_DIRS);
+            !is_ignored_dir && !filter.is_excluded(relative)
+        });
+    for entry in walker {
+        let entry = entry?;
+        if entry.file_type().is_dir() {
+            continue;
+        }
+
+        let relative = entry.path().strip_prefix(root)?;
+        if is_ignored(relative, DIFF_IGNORED_FILES) || !filter.is_included(relative) {
+            continue;
+        }
+
+        let path = relative
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        files.push((path, entry.into_path()));
+    }
+    Ok(files)
+}
+
+// read_file returns the contents of a file, or the target of a symbolic link.
+pub(crate) fn read_file(path: &Path) -> CargoResult<Vec<u8>> {
+    let metadata = fs::symlink_metadata(path)
+        .with_context(|| format!("Unable to read `{}`.", path.display()))?;
+    let data = if metadata.file_type().is_symlink() {
+        let target = fs::read_link(path)?;
+        target.to_string_lossy().into_owned().into_bytes()
+    } else {
+        fs::read(path).with_context(|| format!("Unable to read `{}`.", path.display()))?
+    };
+    Ok(data)
+}
+
+// diff_text returns the hunks of the unified diff between two texts, followed by the number of
+// inserted and deleted lines.
+fn diff_text(old: &str, new: &str) -> (String, usize, usize) {
+    let input = InternedInput::new(lines_with_terminator(old), lines_with_terminator(new));
+    let mut changes: Vec<(Range<u32>, Range<u32>)> = vec![];
+    diff(
+        Algorithm::Histogram,
+        &input,
+        |before: Range<u32>, after: Range<u32>| changes.push((before, after)),
+    );
+
+    let insertions = changes.iter().map(|(_, a)| a.len()).sum();
+    let deletions = changes.iter().map(|(b, _)| b.len()).sum();
+
+    let line = |token| input.interner[token];
+    let mut hunks = String::new();
+    let mut rest = &changes[..];
+    while !rest.is_empty() {
+        // Changes separated by less than twice the context share a hunk.
+        let mut count = 1;
+        while count < rest.len() && rest[count].0.start - rest[count - 1].0.end <= 2 * CONTEXT_LINES
+        {
+            count += 1;
+        }
+        let (hunk, tail) = rest.split_at(count);
+        rest = tail;
+
+        let (first_before, first_after) = &hunk[0];
+        let (last_before, last_after) = &hunk[count - 1];
+        let leading = first_before.start.min(CONTEXT_LINES);
+        let trailing = (input.before.len() as u32 - last_before.end).min(CONTEXT_LINES);
+        let before = first_before.start - leading..last_before.end + trailing;
+        let after = first_after.start - leading..last_after.end + trailing;
+
+        writeln!(
+            hunks,
+            "@@ -{} +{} @@",
+            hunk_range(&before),
+            hunk_range(&after)
+        )
+        .unwrap();
+
+        let mut pos = before.start;
+        for (b, a) in hunk {
+            for &token in &input.before[pos as usize..b.start as usize] {
+                push_line(&mut hunks, ' ', line(token));
+            }
+            for &token in &input.before[b.start as usize..b.end as usize] {
+                push_line(&mut hunks, '-', line(token));
+            }
+            for &token in &input.after[a.start as usize..a.end as usize] {
+                push_line(&mut hunks, '+', line(token));
+            }
+            pos = b.end;
+        }
+        for &token in &input.before[pos as usize..before.end as usize] {
+            push_line(&mut hunks, ' ', line(token));
+        }
+    }
+
+    (hunks, insertions, deletions)
+}
+
+// hunk_range formats a range of lines for a hunk header. Empty ranges refer to the line before.
+fn hunk_range(range: &Range<u32>) -> String {
+    match range.len() {
+        0 => format!("{},0", range.start),
+        1 => format!("{}", range.start + 1),
+        len => format!("{},{}", range.start + 1, len),
+    }
+}
+
+fn push_line(out: &mut String, prefix: char, line: &str) {
+    out.push(prefix);
+    out.push_str(line);
+    if !line.ends_with('\n') {
+        out.push_str("\n\\ No newline at end of file\n");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_diff_text() {
+        let old = "a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n";
+        let new = "a\nB\nc\nd\ne\nf\ng\nh\ni\nj\nk";
+
+        let (hunks, insertions, deletions) = diff_text(old, new);
+
+        let expected = "\
+@@ -1,5 +1,5 @@
+ a
+-b
++B
+ c
+ d
+ e
+@@ -8,3 +8,4 @@
+ h
+ i
+ j
++k
+\\ No newline at end of file
+";
+        assert_eq!(expected, hunks);
+        assert_eq!((2, 1), (insertions, deletions));
+    }
+
+    #[test]
+    fn test_diff_directories() {
+        let old = tempdir().unwrap();
+        let new = tempdir().unwrap();
+        fs::create_dir(old.path().join("src")).unwrap();
+        fs::create_dir(new.path().join("src")).unwrap();
+        fs::write(old.path().join("src/lib.rs"), "fn a() {}\n").unwrap();
+        fs::write(new.path().join("src/lib.rs"), "fn b() {}\n").unwrap();
+        fs::write(old.path().join("README.md"), "foo\n").unwrap();
+        fs::write(new.path().join("README.md"), "foo\n").unwrap();
+        fs::write(old.path().join("old.txt"), "old\n").unwrap();
+        fs::write(new.path().join("logo.png"), b"\x89PNG\0").unwrap();
+        fs::write(new.path().join(".cargo_vcs_info.json"), "{}").unwrap();
+        fs::create_dir_all(new.path().join("target/debug")).unwrap();
+        fs::write(new.path().join("target/debug/foo"), "foo").unwrap();
+
+        let package_diff = PackageDiff {
+            name: "foo".to_string(),
+            version: semver::Version::new(0, 1, 0),
+            old: "foo-0.1.0".to_string(),
+            new: "foo-0.2.0".to_string(),
+            files: diff_directories(old.path(), new.path(), &FileFilter::default()).unwrap(),
+        };
+
+        let mut unified = vec![];
+        package_diff.write_unified(&mut unified).unwrap();
+        let expected = "\
+Binary files /dev/null and foo-0.2.0/logo.png differ
+--- foo-0.1.0/old.txt
++++ /dev/null
+@@ -1 +0,0 @@
+-old
+--- foo-0.1.0/src/lib.rs
++++ foo-0.2.0/src/lib.rs
+@@ -1 +1 @@
+-fn a() {}
++fn b() {}
+";
+        assert_eq!(expected, String::from_utf8(unified).unwrap());
+
+        let mut stat = vec![];
+        package_diff.write_stat(&mut stat).unwrap();
+        let expected = " logo.png   | Bin
+ old.txt    | 1 -
+ src/lib.rs | 2 +-
+ 3 files changed, 1 insertion(+), 2 deletions(-)
+";
+        assert_eq!(expected, String::from_utf8(stat).unwrap());
+    }
+
+    #[test]
+    fn test_write_patch() {
+        let old = tempdir().unwrap();
+        let new = tempdir().unwrap();
+        fs::write(old.path().join("lib.rs"), "fn a() {}\n").unwrap();
+        fs::write(new.path().join("lib.rs"), "fn b() {}\n").unwrap();
+        let package_diff = PackageDiff {
+            name: "foo".to_string(),
+            version: semver::Version::new(0, 1, 0),
+            old: "a".to_string(),
+            new: "b".to_string(),
+            files: diff_directories(old.path(), new.path(), &FileFilter::default()).unwrap(),
+        };
+
+        let mut patch = vec![];
+        package_diff
+            .write_patch(
+                &mut patch,
+                "Jane Doe <jane@example.com>",
+                SystemTime::UNIX_EPOCH,
+                "Local changes to foo 0.1.0",
+            )
+            .unwrap();
+
+        let expected = "\
+From 0000000000000000000000000000000000000000 Mon Sep 17 00:00:00 2001
+From: Jane Doe <jane@example.com>
+Date: Thu, 1 Jan 1970 00:00:00 -0000
+Subject: [PATCH] Local changes to foo 0.1.0
+
+---
+ lib.rs | 2 +-
+ 1 file changed, 1 insertion(+), 1 deletion(-)
+
+--- a/lib.rs
++++ b/lib.rs
+@@ -1 +1 @@
+-fn a() {}
++fn b() {}
+";
+        assert_eq!(expected, String::from_utf8(patch).unwrap());
+    }
+}
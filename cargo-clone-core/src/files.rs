@@ -0,0 +1,264 @@
+// Copyright 2015 Jan Likar.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, bail};
+use cargo::CargoResult;
+use cargo::core::Package;
+use cargo::util::context::GlobalContext;
+use walkdir::WalkDir;
+
+use crate::crate_file::UnpackedCrate;
+use crate::{CopyOptions, IGNORED_FILES, subpath_root};
+
+/// The files of a package, read where its source or cargo's cache keeps it, see
+/// [`Cloner::files`].
+///
+/// [`Cloner::files`]: crate::Cloner::files
+#[derive(Debug)]
+pub struct PackageFiles {
+    name: String,
+    version: semver::Version,
+    /// Directory the paths are relative to: the package root, or its subpath.
+    root: PathBuf,
+    files: Vec<String>,
+    /// Keeps the verified archive of the package unpacked while its files are read.
+    _verified: Option<UnpackedCrate>,
+}
+
+impl PackageFiles {
+    // new lists the files of `pkg` which would be cloned with `options`, relative to the subpath
+    // if any. Files whose path is not valid UTF-8 are skipped with a warning. `verified` is the
+    // archive `pkg` was read from, if it was unpacked to verify its checksum.
+    pub(crate) fn new(
+        context: &GlobalContext,
+        pkg: &Package,
+        options: &CopyOptions,
+        verified: Option<UnpackedCrate>,
+    ) -> CargoResult<Self> {
+        let package_root = pkg.root();
+        let root = subpath_root(package_root, options)?;
+
+        let mut files = vec![];
+        let walker = WalkDir::new(&root).follow_links(options.follow_symlinks);
+        for entry in walker {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    let path = err.path().unwrap_or(&root).to_owned();
+                    if options.skip_unreadable {
+                        context
+                            .shell()
+                            .warn(format!("Skipping `{}`: {}", path.display(), err))?;
+                        continue;
+                    }
+                    return Err(err)
+                        .with_context(|| format!("Unable to read `{}`.", path.display()));
+                }
+            };
+            if entry.file_type().is_dir() {
+                continue;
+            }
+
+            let relative = entry.path().strip_prefix(package_root)?;
+            if IGNORED_FILES.iter().any(|f| relative == Path::new(f))
+                || options.filter.is_excluded(relative)
+                || !options.filter.is_included(relative)
+            {
+                continue;
+            }
+
+            // A subpath which is a single file is listed by its name.
+            let path = match entry.path().strip_prefix(&root)? {
+                path if path.as_os_str().is_empty() => Path::new(entry.file_name()),
+                path => path,
+            };
+            let Some(components) = path
+                .components()
+                .map(|c| c.as_os_str().to_str())
+                .collect::<Option<Vec<_>>>()
+            else {
+                context.shell().warn(format!(
+                    "Skipping `{}`: its path is not valid UTF-8",
+                    entry.path().display()
+                ))?;
+                continue;
+            };
+            files.push(components.join("/"));
+        }
+        files.sort();
+
+        let root = match root.is_dir() {
+            true => root,
+            false => root.parent().unwrap_or(package_root).to_owned(),
+        };
+        Ok(Self {
+            name: pkg.name().to_string(),
+            version: pkg.version().clone(),
+            root,
+            files,
+            _verified: verified,
+        })
+    }
+
+    /// Name of the package.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Version of the package.
+    pub fn version(&self) -> &semver::Version {
+        &self.version
+    }
+
+    /// Paths of the files and symbolic links of the package, relative to its root,
+    /// separated by `/` and sorted.
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    /// Opens the file at `path`, relative to the package root and separated by `/`, to stream
+    /// its contents.
+    ///
+    /// Symbolic links are followed, as long as they point inside the package.
+    pub fn open(&self, path: &str) -> CargoResult<File> {
+        let (name, version) = (&self.name, &self.version);
+        let path = path.trim_start_matches("./");
+        if self
+            .files
+            .binary_search_by(|f| f.as_str().cmp(path))
+            .is_err()
+        {
+            if self.root.join(path).is_dir() && !path.is_empty() {
+                bail!("`{path}` is a directory of {name} {version}, not a file.");
+            }
+            bail!("`{path}` does not exist in {name} {version}.");
+        }
+
+        let root = fs::canonicalize(&self.root)?;
+        let file = fs::canonicalize(root.join(path))
+            .with_context(|| format!("Unable to read `{path}` of {name} {version}."))?;
+        if !file.starts_with(&root) {
+            bail!("`{path}` of {name} {version} is a link outside of the package.");
+        }
+        File::open(&file).with_context(|| format!("Unable to read `{path}` of {name} {version}."))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+
+    use cargo::core::SourceId;
+    use cargo::sources::PathSource;
+    use tempfile::TempDir;
+
+    use crate::FileFilter;
+
+    fn package(dir: &TempDir) -> Package {
+        let root = dir.path().join("foo");
+        fs::create_dir_all(root.join("src/bin")).unwrap();
+        fs::write(
+            root.join("Cargo.toml"),
+            "[package]\nname = \"foo\"\nversion = \"0.1.0\"\n",
+        )
+        .unwrap();
+        fs::write(root.join("src/lib.rs"), "pub fn foo() {}").unwrap();
+        fs::write(root.join("src/bin/foo.rs"), "fn main() {}").unwrap();
+        fs::write(root.join(".cargo-ok"), "").unwrap();
+
+        let context = GlobalContext::default().unwrap();
+        let srcid = SourceId::for_path(&root).unwrap();
+        PathSource::new(&root, srcid, &context)
+            .root_package()
+            .unwrap()
+    }
+
+    fn read(files: &PackageFiles, path: &str) -> String {
+        let mut contents = String::new();
+        files
+            .open(path)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        contents
+    }
+
+    #[test]
+    fn test_files() {
+        let dir = TempDir::new().unwrap();
+        let context = GlobalContext::default().unwrap();
+        let files =
+            PackageFiles::new(&context, &package(&dir), &CopyOptions::default(), None).unwrap();
+
+        assert_eq!(
+            ["Cargo.toml", "src/bin/foo.rs", "src/lib.rs"],
+            files.files()
+        );
+        assert_eq!("pub fn foo() {}", read(&files, "src/lib.rs"));
+        assert!(read(&files, "./Cargo.toml").starts_with("[package]"));
+    }
+
+    #[test]
+    fn test_files_filtered() {
+        let dir = TempDir::new().unwrap();
+        let context = GlobalContext::default().unwrap();
+        let options = CopyOptions {
+            filter: FileFilter::new(&[], &["src/bin".to_string()]).unwrap(),
+            subpath: Some(PathBuf::from("src")),
+            ..CopyOptions::default()
+        };
+        let files = PackageFiles::new(&context, &package(&dir), &options, None).unwrap();
+
+        assert_eq!(["lib.rs"], files.files());
+        assert_eq!("pub fn foo() {}", read(&files, "lib.rs"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_files_not_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let dir = TempDir::new().unwrap();
+        let pkg = package(&dir);
+        fs::write(pkg.root().join(OsStr::from_bytes(b"src/\xff.rs")), "").unwrap();
+        let context = GlobalContext::default().unwrap();
+        let files = PackageFiles::new(&context, &pkg, &CopyOptions::default(), None).unwrap();
+
+        assert_eq!(
+            ["Cargo.toml", "src/bin/foo.rs", "src/lib.rs"],
+            files.files()
+        );
+    }
+
+    #[test]
+    fn test_open_missing() {
+        let dir = TempDir::new().unwrap();
+        let context = GlobalContext::default().unwrap();
+        let files =
+            PackageFiles::new(&context, &package(&dir), &CopyOptions::default(), None).unwrap();
+
+        assert_eq!(
+            "`src/main.rs` does not exist in foo 0.1.0.",
+            files.open("src/main.rs").unwrap_err().to_string()
+        );
+        assert_eq!(
+            "`src/bin` is a directory of foo 0.1.0, not a file.",
+            files.open("src/bin").unwrap_err().to_string()
+        );
+        assert_eq!(
+            "`.cargo-ok` does not exist in foo 0.1.0.",
+            files.open(".cargo-ok").unwrap_err().to_string()
+        );
+    }
+}
@@ -0,0 +1,136 @@
+// Copyright 2015 Jan Likar.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::path::Path;
+
+use anyhow::Context;
+use cargo::CargoResult;
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+/// Predefined sets of file filters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterPreset {
+    /// Only the manifest, the build script and `src/`.
+    SourcesOnly,
+    /// Everything except tests, benchmarks and examples.
+    NoDevFiles,
+}
+
+impl FilterPreset {
+    pub(crate) fn includes(self) -> &'static [&'static str] {
+        match self {
+            FilterPreset::SourcesOnly => &["Cargo.toml", "build.rs", "src"],
+            FilterPreset::NoDevFiles => &[],
+        }
+    }
+
+    pub(crate) fn excludes(self) -> &'static [&'static str] {
+        match self {
+            FilterPreset::SourcesOnly => &[],
+            FilterPreset::NoDevFiles => &["tests", "benches", "examples"],
+        }
+    }
+}
+
+/// Glob patterns selecting which files of a package are cloned.
+///
+/// A pattern matches a path if it matches the path itself or one of its parent directories,
+/// so `src` selects everything inside `src/`. `*` does not match `/`, use `**` for that.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct FileFilter {
+    include: Option<GlobSet>,
+    exclude: Option<GlobSet>,
+}
+
+impl FileFilter {
+    pub(crate) fn new(include: &[String], exclude: &[String]) -> CargoResult<Self> {
+        Ok(Self {
+            include: build_glob_set(include)?,
+            exclude: build_glob_set(exclude)?,
+        })
+    }
+
+    /// Whether `path` (relative to the package root) is matched by an include pattern.
+    /// Without include patterns, every path is included.
+    pub(crate) fn is_included(&self, path: &Path) -> bool {
+        match &self.include {
+            Some(include) => matches(include, path),
+            None => true,
+        }
+    }
+
+    /// Whether `path` (relative to the package root) is matched by an exclude pattern.
+    pub(crate) fn is_excluded(&self, path: &Path) -> bool {
+        match &self.exclude {
+            Some(exclude) => matches(exclude, path),
+            None => false,
+        }
+    }
+}
+
+fn build_glob_set(patterns: &[String]) -> CargoResult<Option<GlobSet>> {
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        let glob = GlobBuilder::new(pattern.trim_end_matches('/'))
+            .literal_separator(true)
+            .build()
+            .with_context(|| format!("Invalid file pattern: `{pattern}`."))?;
+        builder.add(glob);
+    }
+    Ok(Some(builder.build()?))
+}
+
+fn matches(set: &GlobSet, path: &Path) -> bool {
+    path.ancestors()
+        .filter(|p| !p.as_os_str().is_empty())
+        .any(|p| set.is_match(p))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(include: &[&str], exclude: &[&str]) -> FileFilter {
+        let include: Vec<String> = include.iter().map(|p| p.to_string()).collect();
+        let exclude: Vec<String> = exclude.iter().map(|p| p.to_string()).collect();
+        FileFilter::new(&include, &exclude).unwrap()
+    }
+
+    #[test]
+    fn test_include() {
+        let filter = filter(&["src/", "*.toml"], &[]);
+
+        assert!(filter.is_included(Path::new("src")));
+        assert!(filter.is_included(Path::new("src/codec/mod.rs")));
+        assert!(filter.is_included(Path::new("Cargo.toml")));
+        assert!(!filter.is_included(Path::new("tests/data/Cargo.toml")));
+        assert!(!filter.is_included(Path::new("README.md")));
+    }
+
+    #[test]
+    fn test_exclude() {
+        let filter = filter(&[], &["benches", "tests/fixtures", "**/*.bin"]);
+
+        assert!(filter.is_included(Path::new("README.md")));
+        assert!(filter.is_excluded(Path::new("benches/bench.rs")));
+        assert!(filter.is_excluded(Path::new("tests/fixtures/a/b")));
+        assert!(filter.is_excluded(Path::new("data/large.bin")));
+        assert!(!filter.is_excluded(Path::new("tests/test.rs")));
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        let err = FileFilter::new(&["src/[".to_string()], &[]).unwrap_err();
+
+        assert_eq!("Invalid file pattern: `src/[`.", err.to_string());
+    }
+}
@@ -0,0 +1,103 @@
+// Copyright 2015 Jan Likar.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{Context, bail};
+use cargo::CargoResult;
+use jiff::Timestamp;
+
+/// Creates an empty git repository in `dir`.
+pub(crate) fn init(dir: &Path) -> CargoResult<()> {
+    run(git(dir).args(["init", "--quiet"]))
+}
+
+/// Commits every file in `dir`, including files matched by `.gitignore`.
+/// If `date` is set, it is used as the author and committer date.
+pub(crate) fn commit_all(dir: &Path, message: &str, date: Option<Timestamp>) -> CargoResult<()> {
+    run(git(dir).args(["add", "--all", "--force", "."]))?;
+
+    let mut commit = git(dir);
+    commit.args([
+        "commit",
+        "--quiet",
+        "--allow-empty",
+        "--no-verify",
+        "--message",
+        message,
+    ]);
+    if let Some(date) = date {
+        let date = format!("{} +0000", date.as_second());
+        commit
+            .env("GIT_AUTHOR_DATE", &date)
+            .env("GIT_COMMITTER_DATE", &date);
+    }
+    run(&mut commit)
+}
+
+/// Tags the current commit of the repository in `dir`.
+pub(crate) fn tag(dir: &Path, name: &str) -> CargoResult<()> {
+    run(git(dir).args(["tag", name]))
+}
+
+/// Returns the changes of the worktree of the repository in `dir` which are not committed, in
+/// the format of `git status --porcelain`, empty if the worktree is clean.
+pub(crate) fn status(dir: &Path) -> CargoResult<String> {
+    output(git(dir).args(["status", "--porcelain"]))
+}
+
+/// Returns the commit of the default branch of the remote repository at `url`.
+pub(crate) fn remote_head(url: &str) -> CargoResult<String> {
+    let refs = output(Command::new("git").args(["ls-remote", url, "HEAD"]))?;
+    refs.split_whitespace()
+        .next()
+        .map(str::to_owned)
+        .with_context(|| format!("`{url}` has no default branch."))
+}
+
+// Configuration of the repositories of pristine sources, which must not depend on the git
+// configuration of the user: commits are made by cargo-clone, and never signed, since signing
+// may prompt for a passphrase.
+const CONFIG: &[&str] = &[
+    "-c",
+    "user.name=cargo-clone",
+    "-c",
+    "user.email=cargo-clone@localhost",
+    "-c",
+    "commit.gpgsign=false",
+    "-c",
+    "tag.gpgsign=false",
+];
+
+fn git(dir: &Path) -> Command {
+    let mut command = Command::new("git");
+    command.current_dir(dir).args(CONFIG);
+    command
+}
+
+fn run(command: &mut Command) -> CargoResult<()> {
+    output(command).map(|_| ())
+}
+
+// output runs the command and returns its standard output.
+fn output(command: &mut Command) -> CargoResult<String> {
+    let output = command
+        .output()
+        .with_context(|| format!("Unable to run {command:?}."))?;
+
+    if !output.status.success() {
+        bail!(
+            "{:?} failed: {}",
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
@@ -10,49 +10,180 @@
 
 #![warn(missing_docs)]
 
+mod archive;
+mod bisect;
+mod cache;
 mod cloner_builder;
+mod crate_file;
+mod diff;
+mod files;
+mod filter;
+mod git;
+mod lock;
 mod source;
+mod sync;
 
+pub use archive::ArchiveFormat;
+pub use bisect::{BisectCommand, BisectOutcome, Bisection};
 pub use cloner_builder::*;
+pub use diff::{FileChange, FileDiff, PackageDiff};
+pub use files::PackageFiles;
+pub use filter::FilterPreset;
+pub use lock::CloneLock;
 pub use source::*;
+pub use sync::{CloneManifest, SyncStatus, SyncedCrate};
 
+use crate_file::{UnpackedCrate, unpack_crate_data, unpack_crate_file};
+use filter::FileFilter;
+use source::CargoSource;
+use sync::CloneRecord;
+
+use std::cell::RefCell;
 use std::fs;
-use std::path::Path;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Component, Path, PathBuf};
 use std::process::Command;
 
 use anyhow::{Context, bail};
 
-use cargo::core::Package;
 use cargo::core::dependency::Dependency;
+use cargo::core::{Package, PackageIdSpec, SourceKind, Summary};
 use cargo::sources::registry::IndexSummary;
 use cargo::sources::source::QueryKind;
 use cargo::sources::source::Source;
-use cargo::sources::{PathSource, SourceConfigMap};
+use cargo::sources::{CRATES_IO_INDEX, PathSource, SourceConfigMap};
+use cargo::util::HumanBytes;
 use cargo::util::cache_lock::CacheLockMode;
 use cargo::util::context::GlobalContext;
+use filetime::FileTime;
 use semver::VersionReq;
+use tempfile::TempDir;
 
-use walkdir::WalkDir;
+use walkdir::{DirEntry, WalkDir};
 
 // Re-export cargo types.
 pub use cargo::{core::SourceId, util::CargoResult};
 
+// Sources loaded by `Cloner::load_sources`.
+type Sources<'a> = Vec<Box<dyn Source + 'a>>;
+
 /// Rust crate.
-#[derive(PartialEq, Eq, Debug)]
+#[derive(Clone, PartialEq, Eq, Debug)]
 pub struct Crate {
     name: String,
     version: Option<String>,
+    source: Option<ClonerSource>,
+    destination: Option<PathBuf>,
+    checksum: Option<String>,
 }
 
 impl Crate {
     /// Create a new [`Crate`].
     /// If `version` is not specified, the latest version is chosen.
     pub fn new(name: String, version: Option<String>) -> Crate {
-        Crate { name, version }
+        Crate {
+            name,
+            version,
+            source: None,
+            destination: None,
+            checksum: None,
+        }
+    }
+
+    /// Clone the crate from `source` instead of the source of the [`Cloner`].
+    pub fn with_source(self, source: ClonerSource) -> Crate {
+        Crate {
+            source: Some(source),
+            ..self
+        }
+    }
+
+    /// Clone the crate into `destination`, relative to the directory of the [`Cloner`],
+    /// instead of a subdirectory named after the crate.
+    ///
+    /// When cloning every matching version, the versions are cloned into subdirectories of
+    /// `destination`.
+    pub fn with_destination(self, destination: impl Into<PathBuf>) -> Crate {
+        Crate {
+            destination: Some(destination.into()),
+            ..self
+        }
+    }
+
+    /// Only clone the crate if the SHA-256 checksum of its `.crate` archive is `checksum`,
+    /// according to its source and to the downloaded archive.
+    pub fn with_checksum(self, checksum: impl Into<String>) -> Crate {
+        Crate {
+            checksum: Some(checksum.into().to_ascii_lowercase()),
+            ..self
+        }
+    }
+
+    /// Name of the crate.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Destination of the crate, if it was specified.
+    pub fn destination(&self) -> Option<&Path> {
+        self.destination.as_deref()
     }
 }
 
+/// How a version is selected among the versions matching the requested one.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum VersionSelection {
+    /// Select the newest matching version.
+    #[default]
+    Newest,
+    /// Select the oldest matching version, e.g. to test minimal versions of dependencies.
+    Oldest,
+    /// Only accept exact versions, such as `foo@1.2.3`.
+    ExactOnly,
+}
+
+/// A package that has been cloned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClonedPackage {
+    /// Name of the package.
+    pub name: String,
+    /// Version of the package.
+    pub version: semver::Version,
+    /// Source the package was requested from.
+    pub source_id: SourceId,
+    /// Source that served the package.
+    /// It differs from `source_id` when the source is replaced in cargo's configuration.
+    pub replaced_source_id: SourceId,
+    /// Checksum of the `.crate` archive, as recorded by the source.
+    pub checksum: Option<String>,
+    /// Directory the package was cloned into.
+    pub path: PathBuf,
+    /// Number of files which were not cloned because of the file filters.
+    pub skipped_files: u64,
+    /// Total size of the files which were not cloned because of the file filters.
+    pub skipped_bytes: u64,
+}
+
+/// A package which would be cloned, see [`Cloner::plan`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedClone {
+    /// Name of the package.
+    pub name: String,
+    /// Version of the package.
+    pub version: semver::Version,
+    /// Source the package is requested from.
+    pub source_id: SourceId,
+    /// Checksum of the `.crate` archive, as recorded by the source.
+    pub checksum: Option<String>,
+    /// Directory the package would be cloned into.
+    pub path: PathBuf,
+    /// Git repository the package would be cloned from, when cloning from git repositories.
+    pub repository: Option<String>,
+    /// Current commit of the default branch of `repository`, as reported by `git ls-remote`.
+    /// It is only set when network access is allowed.
+    pub commit: Option<String>,
+}
+
 /// Clones a crate.
 pub struct Cloner {
     /// Cargo context.
@@ -60,10 +191,30 @@ pub struct Cloner {
     /// Directory where the crates will be cloned.
     /// Each crate is cloned into a subdirectory of this directory.
     pub(crate) directory: PathBuf,
-    /// Where the crates will be cloned from.
-    pub(crate) srcid: SourceId,
+    /// Where the crates will be cloned from, unless they have their own source.
+    pub(crate) source: ClonerSource,
+    /// How to treat source replacement configured for the sources.
+    pub(crate) source_replacement: SourceReplacement,
     /// If true, use `git` to clone the git repository present in the manifest metadata.
     pub(crate) use_git: bool,
+    /// If true, clone every matching version into a git repository with one commit per version.
+    pub(crate) history: bool,
+    /// If true, initialize a git repository in the destination and commit the pristine sources.
+    pub(crate) git_init: bool,
+    /// If true, clone every matching version into `name-version` subdirectories.
+    pub(crate) all_matching: bool,
+    /// If set, each package is written as an archive instead of a directory.
+    pub(crate) archive: Option<ArchiveFormat>,
+    /// Template of the subdirectories the crates are cloned into, e.g. `{name}-{version}`.
+    pub(crate) dir_template: Option<String>,
+    /// How a version is selected among the matching ones.
+    pub(crate) version_selection: VersionSelection,
+    /// If set, the locked versions are cloned instead of selecting a version.
+    pub(crate) lock: Option<CloneLock>,
+    /// How to copy the files of the package.
+    pub(crate) copy_options: CopyOptions,
+    /// `.crate` archives unpacked so far, kept alive while their packages are cloned.
+    pub(crate) crate_files: RefCell<Vec<(CargoSource, UnpackedCrate)>>,
 }
 
 impl Cloner {
@@ -76,280 +227,2094 @@ impl Cloner {
 
     /// Clone the specified crate from registry or git repository.
     /// The crate is cloned in the directory specified by the [`ClonerBuilder`].
-    pub fn clone_in_dir(&self, crate_: &Crate) -> CargoResult<()> {
+    ///
+    /// Not supported when cloning every matching version, use [`Cloner::clone`] instead.
+    pub fn clone_in_dir(&self, crate_: &Crate) -> CargoResult<ClonedPackage> {
+        if self.all_matching {
+            bail!("Every matching version can only be cloned into subdirectories.");
+        }
+
         let _lock = self
             .context
             .acquire_package_cache_lock(CacheLockMode::DownloadExclusive)?;
 
-        let mut src = get_source(&self.srcid, &self.context)?;
+        let (mut sources, _) = self.load_sources(&[crate_])?;
 
-        self.clone_in(crate_, &self.directory, &mut src)
+        let src = &mut sources[0];
+        let summary = self.select(crate_, src)?;
+        self.clone_in(crate_, summary, &self.directory, src)
     }
 
     /// Clone the specified crates from registry or git repository.
-    /// Each crate is cloned in a subdirectory named as the crate name.
-    pub fn clone(&self, crates: &[Crate]) -> CargoResult<()> {
+    /// Each crate is cloned in its destination, if specified, or in a subdirectory named after
+    /// the directory template, the crate name, or `name-version` when cloning every matching
+    /// version.
+    pub fn clone(&self, crates: &[Crate]) -> CargoResult<Vec<ClonedPackage>> {
         let _lock = self
             .context
             .acquire_package_cache_lock(CacheLockMode::DownloadExclusive)?;
 
-        let mut src = get_source(&self.srcid, &self.context)?;
+        let (mut sources, source_of) = self.load_sources(&crates.iter().collect::<Vec<_>>())?;
 
-        for crate_ in crates {
-            let mut dest_path = self.directory.clone();
+        let mut cloned = Vec::with_capacity(crates.len());
+        for (crate_, &index) in crates.iter().zip(&source_of) {
+            let src = &mut sources[index];
+            if self.all_matching {
+                let directory = self.crate_dir(crate_, None)?;
+                cloned.extend(self.clone_all_matching(crate_, &directory, src)?);
+            } else {
+                let summary = self.select(crate_, src)?;
+                let directory = self.crate_dir(crate_, Some(&summary))?;
+                cloned.push(self.clone_in(crate_, summary, &directory, src)?);
+            }
+        }
+
+        Ok(cloned)
+    }
+
+    /// Resolve the specified crates like [`Cloner::clone`], without cloning them, and check
+    /// that nothing is in the way of their destinations.
+    ///
+    /// Nothing is downloaded, except when cloning from git repositories: the package is then
+    /// downloaded into cargo's cache to read its repository from its manifest.
+    /// Pinned and locked checksums are compared with the checksums recorded by the sources,
+    /// but archives are only verified against them when cloning.
+    pub fn plan(&self, crates: &[Crate]) -> CargoResult<Vec<PlannedClone>> {
+        let _lock = self
+            .context
+            .acquire_package_cache_lock(CacheLockMode::DownloadExclusive)?;
 
-            dest_path.push(&crate_.name);
+        let (mut sources, source_of) = self.load_sources(&crates.iter().collect::<Vec<_>>())?;
 
-            self.clone_in(crate_, &dest_path, &mut src)?;
+        let mut planned = Vec::with_capacity(crates.len());
+        for (crate_, &index) in crates.iter().zip(&source_of) {
+            let src = &mut sources[index];
+            let summary = match self.all_matching {
+                true => None,
+                false => Some(self.select(crate_, src)?),
+            };
+            let directory = self.crate_dir(crate_, summary.as_ref())?;
+            planned.extend(self.plan_crate(crate_, summary, &directory, src)?);
         }
 
-        Ok(())
+        check_destinations(&planned)?;
+        Ok(planned)
     }
 
-    fn clone_in<'a, T>(&self, crate_: &Crate, dest_path: &Path, src: &mut T) -> CargoResult<()>
-    where
-        T: Source + 'a,
-    {
-        if !dest_path.exists() {
-            fs::create_dir_all(dest_path)?;
+    /// Resolve the specified crate like [`Cloner::clone_in_dir`], without cloning it.
+    /// See [`Cloner::plan`].
+    pub fn plan_in_dir(&self, crate_: &Crate) -> CargoResult<PlannedClone> {
+        if self.all_matching {
+            bail!("Every matching version can only be cloned into subdirectories.");
         }
 
-        self.context
-            .shell()
-            .verbose(|s| s.note(format!("Cloning into {:?}", &self.directory)))?;
+        let _lock = self
+            .context
+            .acquire_package_cache_lock(CacheLockMode::DownloadExclusive)?;
 
-        // Cloning into an existing directory is only allowed if the directory is empty.
-        let is_empty = dest_path.read_dir()?.next().is_none();
-        if !is_empty {
-            bail!(
-                "destination path '{}' already exists and is not an empty directory.",
-                dest_path.display()
-            );
-        }
+        let (mut sources, _) = self.load_sources(&[crate_])?;
 
-        self.clone_single(crate_, dest_path, src)
+        let src = &mut sources[0];
+        let summary = self.select(crate_, src)?;
+        let planned = self.plan_crate(crate_, Some(summary), &self.directory, src)?;
+        check_destinations(&planned)?;
+        Ok(planned
+            .into_iter()
+            .next()
+            .expect("a single version is planned"))
     }
 
-    fn clone_single<'a, T>(&self, crate_: &Crate, dest_path: &Path, src: &mut T) -> CargoResult<()>
-    where
-        T: Source + 'a,
-    {
-        let pkg = select_pkg(&self.context, src, &crate_.name, crate_.version.as_deref())?;
+    /// Compare two versions of a package.
+    /// Both packages are cloned into a temporary directory first, so the file filters apply.
+    pub fn diff(&self, old: &Crate, new: &Crate) -> CargoResult<PackageDiff> {
+        if self.use_git {
+            bail!("Cannot diff packages cloned from their git repository.");
+        }
+
+        let _lock = self
+            .context
+            .acquire_package_cache_lock(CacheLockMode::DownloadExclusive)?;
+
+        let (mut sources, source_of) = self.load_sources(&[old, new])?;
+
+        let dir = TempDir::new().context("Unable to create a temporary directory.")?;
+        let (old_path, new_path) = (dir.path().join("old"), dir.path().join("new"));
+        fs::create_dir(&old_path)?;
+        fs::create_dir(&new_path)?;
+        let old = self.clone_single(old, &old_path, &mut sources[source_of[0]])?;
+        let new = self.clone_single(new, &new_path, &mut sources[source_of[1]])?;
+
+        Ok(PackageDiff {
+            name: old.name.clone(),
+            version: old.version.clone(),
+            old: format!("{}-{}", old.name, old.version),
+            new: format!("{}-{}", new.name, new.version),
+            files: diff::diff_directories(&old.path, &new.path, &self.copy_options.filter)?,
+        })
+    }
 
+    /// Compare the pristine sources of a package with a local directory, e.g. a modified clone.
+    /// If `crate_` is `None`, the package, its version and its source are read from the record
+    /// left in `dir` by [`Cloner::sync`]. Without a record, the package and its version are read
+    /// from the manifest in `dir`, and the source of the cloner is used.
+    /// Paths are prefixed with `a/` and `b/` like in git, so the changes apply to `dir`.
+    pub fn diff_local(&self, crate_: Option<&Crate>, dir: &Path) -> CargoResult<PackageDiff> {
         if self.use_git {
-            let repo = &pkg.manifest().metadata().repository;
+            bail!("Cannot diff packages cloned from their git repository.");
+        }
 
-            if repo.is_none() {
-                bail!(
-                    "Cannot clone {} from git repo because it is not specified in package's manifest.",
-                    &crate_.name
-                )
+        let dir = self.context.cwd().join(dir);
+        let local;
+        let crate_ = match crate_ {
+            Some(crate_) => crate_,
+            None => {
+                local = match CloneRecord::load(&dir)? {
+                    Some(record) => record.crate_(),
+                    None => local_package(&self.context, &dir)?,
+                };
+                &local
             }
+        };
 
-            clone_git_repo(repo.as_ref().unwrap(), dest_path)?;
-        } else {
-            clone_directory(pkg.root(), dest_path)?;
-        }
+        let _lock = self
+            .context
+            .acquire_package_cache_lock(CacheLockMode::DownloadExclusive)?;
 
-        Ok(())
+        let (mut sources, _) = self.load_sources(&[crate_])?;
+
+        let tmp = TempDir::new().context("Unable to create a temporary directory.")?;
+        let pristine_path = tmp.path().join("pristine");
+        fs::create_dir(&pristine_path)?;
+        let pristine = self.clone_single(crate_, &pristine_path, &mut sources[0])?;
+
+        Ok(PackageDiff {
+            name: pristine.name,
+            version: pristine.version,
+            old: "a".to_string(),
+            new: "b".to_string(),
+            files: diff::diff_directories(&pristine.path, &dir, &self.copy_options.filter)?,
+        })
     }
-}
 
-fn get_source<'a>(
-    srcid: &SourceId,
-    context: &'a GlobalContext,
-) -> CargoResult<Box<dyn Source + 'a>> {
-    let mut source = if srcid.is_path() {
-        let path = srcid.url().to_file_path().expect("path must be valid");
-        Box::new(PathSource::new(&path, *srcid, context))
-    } else {
-        let map = SourceConfigMap::new(context)?;
-        map.load(*srcid, &Default::default())?
-    };
+    /// List or read the files of the specified crate without cloning it. The files are read
+    /// where the source or cargo's cache keeps the package, and the file filters and the subpath
+    /// apply. With a pinned or locked checksum, they are read from the verified archive instead,
+    /// unpacked into a temporary directory.
+    pub fn files(&self, crate_: &Crate) -> CargoResult<PackageFiles> {
+        if self.use_git {
+            bail!("Cannot read the files of packages cloned from their git repository.");
+        }
 
-    source.invalidate_cache();
-    Ok(source)
-}
+        let _lock = self
+            .context
+            .acquire_package_cache_lock(CacheLockMode::DownloadExclusive)?;
 
-fn select_pkg<'a, T>(
-    context: &GlobalContext,
-    src: &mut T,
-    name: &str,
-    vers: Option<&str>,
-) -> CargoResult<Package>
-where
-    T: Source + 'a,
-{
-    let dep = Dependency::parse(name, vers, src.source_id())?;
-    let mut summaries = vec![];
+        let (mut sources, _) = self.load_sources(&[crate_])?;
+        let src = &mut sources[0];
 
-    loop {
-        match src.query(&dep, QueryKind::Exact, &mut |summary| {
-            summaries.push(summary)
-        })? {
-            std::task::Poll::Ready(()) => break,
-            std::task::Poll::Pending => src.block_until_ready()?,
+        let summary = self.select(crate_, src)?;
+        if let Some(verified) = self.verify_checksum(crate_, &summary, src)? {
+            let pkg = read_package(&self.context, &verified.root)?;
+            return PackageFiles::new(&self.context, &pkg, &self.copy_options, Some(verified));
         }
+        let pkg = download_pkg(&self.context, src, &summary)?;
+        PackageFiles::new(&self.context, &pkg, &self.copy_options, None)
     }
 
-    let latest = summaries
-        .iter()
-        .filter_map(|idxs| match idxs {
-            IndexSummary::Candidate(s) => Some(s),
-            _ => None,
-        })
-        .max_by_key(|s| s.version());
+    /// Find the first version of a package which has a regression, by binary search over the
+    /// versions matching the requested version. Yanked versions are skipped.
+    ///
+    /// Each tested version is cloned into a temporary directory and passed to `test`,
+    /// e.g. [`BisectCommand::run`]. The package cache is not locked while `test` runs,
+    /// so it may run cargo.
+    pub fn bisect<F>(&self, crate_: &Crate, mut test: F) -> CargoResult<Bisection>
+    where
+        F: FnMut(&ClonedPackage) -> CargoResult<BisectOutcome>,
+    {
+        if self.use_git {
+            bail!("Cannot bisect packages cloned from their git repository.");
+        }
 
-    match latest {
-        Some(l) => {
-            context
-                .shell()
-                .note(format!("Downloading {} {}", name, l.version()))?;
-            let pkg = Box::new(src).download_now(l.package_id(), context)?;
-            Ok(pkg)
+        if crate_.checksum.is_some() {
+            bail!("A checksum can only be pinned when cloning a single version.");
         }
-        None => bail!("Package `{}@{}` not found", name, vers.unwrap_or("*.*.*")),
-    }
-}
 
-fn parse_version_req(version: &str) -> CargoResult<String> {
-    // This function's main purpose is to treat "x.y.z" as "=x.y.z"
-    // so specifying the version in CLI works as expected.
-    let first = version.chars().next();
+        let mut src = get_source(
+            &self.source_id(crate_)?,
+            self.source_replacement,
+            &self.context,
+        )?;
+        let summaries = {
+            let _lock = self
+                .context
+                .acquire_package_cache_lock(CacheLockMode::DownloadExclusive)?;
+            select_all_pkgs(
+                &self.context,
+                &mut src,
+                &crate_.name,
+                crate_.version.as_deref(),
+            )?
+        };
 
-    if first.is_none() {
-        bail!("Version cannot be empty.")
-    };
+        let dir = TempDir::new().context("Unable to create a temporary directory.")?;
+        let (last_good, first_bad, skipped) = bisect::search(&summaries, |summary| {
+            let path = dir
+                .path()
+                .join(format!("{}-{}", summary.name(), summary.version()));
+            fs::create_dir(&path)?;
+            let package = {
+                let _lock = self
+                    .context
+                    .acquire_package_cache_lock(CacheLockMode::DownloadExclusive)?;
+                self.clone_summary(summary.clone(), &path, &mut src, None)?
+            };
 
-    let is_req = "<>=^~".contains(first.unwrap()) || version.contains('*');
+            self.context
+                .shell()
+                .note(format!("Testing {} {}", package.name, package.version))?;
+            let outcome = test(&package)?;
+            self.context.shell().note(format!(
+                "{} {} is {}",
+                package.name,
+                package.version,
+                match outcome {
+                    BisectOutcome::Good => "good",
+                    BisectOutcome::Bad => "bad",
+                    BisectOutcome::Skip => "skipped",
+                }
+            ))?;
 
-    if is_req {
-        let vers = VersionReq::parse(version)
-            .with_context(|| format!("Invalid version requirement: `{version}`."))?;
-        Ok(vers.to_string())
-    } else {
-        Ok(format!("={version}"))
+            fs::remove_dir_all(&path)?;
+            Ok(outcome)
+        })?;
+
+        let version = |i: usize| summaries[i].version().clone();
+        Ok(Bisection {
+            last_good: last_good.map(version),
+            first_bad: first_bad.map(version),
+            skipped: skipped.into_iter().map(version).collect(),
+        })
     }
-}
 
-// clone_directory copies the contents of one directory into another directory, which must
-// already exist.
-fn clone_directory(from: &Path, to: &Path) -> CargoResult<()> {
-    if !to.is_dir() {
-        bail!("Not a directory: {}", to.to_string_lossy());
+    /// Clone the crates of a [`CloneManifest`] which are missing, and report the clones which
+    /// have another version than the selected one. They are updated if `update` is true.
+    ///
+    /// Each clone records its source, version and the checksums of its files in a
+    /// `.cargo-clone.toml` file, which is committed with the pristine sources with `git-init`.
+    /// The version of a clone without this record is read from its `Cargo.toml`.
+    ///
+    /// Updating a clone replaces its files, except its git repository, in which the new version
+    /// is committed when the crate is cloned with `git-init`. It fails if local changes would be
+    /// lost: with `git-init`, the worktree must be clean, and otherwise the files must not have
+    /// changed since the clone was recorded.
+    pub fn sync(&self, manifest: &CloneManifest, update: bool) -> CargoResult<Vec<SyncedCrate>> {
+        if self.use_git
+            || self.history
+            || self.all_matching
+            || self.archive.is_some()
+            || self.copy_options.subpath.is_some()
+        {
+            bail!(
+                "Cannot sync clones of git repositories, histories, every matching version, archives or subpaths."
+            );
+        }
+
+        let _lock = self
+            .context
+            .acquire_package_cache_lock(CacheLockMode::DownloadExclusive)?;
+
+        let (mut sources, source_of) = self.load_sources(&manifest.crates().collect::<Vec<_>>())?;
+
+        let mut synced = Vec::with_capacity(manifest.entries.len());
+        for (entry, &index) in manifest.entries.iter().zip(&source_of) {
+            let src = &mut sources[index];
+            let crate_ = &entry.crate_;
+            let path = self.directory.join(
+                crate_
+                    .destination()
+                    .expect("manifest crates have a destination"),
+            );
+            let git_init = entry.git_init || self.git_init;
+            if git_init && self.copy_options.link {
+                bail!(
+                    "Cannot initialize a git repository in clones whose files are hard links into cargo's cache."
+                );
+            }
+
+            let summary = self.select(crate_, src)?;
+            let version = summary.version().clone();
+
+            let status = if !path.exists() || path.read_dir()?.next().is_none() {
+                self.prepare_destination(&path)?;
+                let package = self.clone_summary(summary, &path, src, None)?;
+                record_pristine(&package, git_init)?;
+                SyncStatus::Cloned
+            } else {
+                let record = CloneRecord::load(&path)?;
+                let (cloned_version, up_to_date) = match &record {
+                    Some(record) => (
+                        record.version.clone(),
+                        record.is_for(&summary, src.source_id()),
+                    ),
+                    None => {
+                        // Clones made before records existed are identified by their manifest.
+                        let cloned = read_package(&self.context, &path)?;
+                        let up_to_date =
+                            cloned.name().as_str() == crate_.name && *cloned.version() == version;
+                        (cloned.version().clone(), up_to_date)
+                    }
+                };
+
+                if up_to_date {
+                    SyncStatus::UpToDate
+                } else if !update {
+                    SyncStatus::Drifted(cloned_version)
+                } else {
+                    check_pristine(
+                        &self.context,
+                        &path,
+                        &crate_.name,
+                        record.as_ref(),
+                        git_init,
+                    )?;
+                    clear_worktree(&path)?;
+                    let package = self.clone_summary(summary, &path, src, None)?;
+                    record_pristine(&package, git_init)?;
+                    SyncStatus::Updated(cloned_version)
+                }
+            };
+
+            synced.push(SyncedCrate {
+                name: crate_.name.clone(),
+                version,
+                path,
+                status,
+            });
+        }
+
+        Ok(synced)
     }
-    for entry in WalkDir::new(from) {
-        let entry = entry.unwrap();
-        let file_type = entry.file_type();
-        let mut dest_path = to.to_owned();
-        dest_path.push(entry.path().strip_prefix(from).unwrap());
 
-        if entry.file_name() == ".cargo-ok" {
-            continue;
+    // crate_dir returns the directory `crate_` is cloned into by `Cloner::clone`, or the
+    // directory of its versions when cloning every matching version. `summary` is the selected
+    // version, which names the directory with a directory template.
+    fn crate_dir(&self, crate_: &Crate, summary: Option<&Summary>) -> CargoResult<PathBuf> {
+        if let Some(destination) = &crate_.destination {
+            check_destination_dir(destination)?;
+            return Ok(self.directory.join(destination));
+        }
+        if self.all_matching {
+            return Ok(self.directory.clone());
         }
 
-        if file_type.is_file() {
-            // .cargo-ok is not wanted in this context
-            fs::copy(entry.path(), &dest_path)?;
-        } else if file_type.is_dir() {
-            if dest_path == to {
-                continue;
+        match &self.dir_template {
+            Some(template) => {
+                let summary = summary.expect("a version is selected to render the template");
+                let version = summary.version().to_string();
+                let name = render_dir_template(template, &crate_.name, &version)?;
+                Ok(self.directory.join(name))
             }
-            fs::create_dir(&dest_path)?;
+            None => Ok(self.directory.join(&crate_.name)),
         }
     }
 
-    Ok(())
-}
+    // version_dir returns the subdirectory of `directory` a version is cloned into, when cloning
+    // every matching version.
+    fn version_dir(&self, directory: &Path, summary: &Summary) -> CargoResult<PathBuf> {
+        let template = self.dir_template.as_deref().unwrap_or("{name}-{version}");
+        let version = summary.version().to_string();
+        Ok(directory.join(render_dir_template(template, &summary.name(), &version)?))
+    }
 
-fn clone_git_repo(repo: &str, to: &Path) -> CargoResult<()> {
-    let status = Command::new("git")
-        .arg("clone")
-        .arg(repo)
-        .arg(to.to_str().unwrap())
-        .status()
-        .context("Failed to clone from git repo.")?;
+    // plan_crate resolves the versions of `crate_` which would be cloned into `dest_path`.
+    // `summary` is the selected version, unless cloning every matching version.
+    fn plan_crate<'a, T>(
+        &self,
+        crate_: &Crate,
+        summary: Option<Summary>,
+        dest_path: &Path,
+        src: &mut T,
+    ) -> CargoResult<Vec<PlannedClone>>
+    where
+        T: Source + 'a,
+    {
+        // The checks of the checksum made before downloading anything are made here too.
+        self.check_pinned_checksum(crate_)?;
+        let source_id = src.source_id();
+        let planned = |summary: &Summary, path: PathBuf| PlannedClone {
+            name: summary.name().to_string(),
+            version: summary.version().clone(),
+            source_id,
+            checksum: summary.checksum().map(str::to_owned),
+            path: match self.archive {
+                Some(format) => format.path(&path),
+                None => path,
+            },
+            repository: None,
+            commit: None,
+        };
 
-    if !status.success() {
-        bail!("Failed to clone from git repo.")
+        if self.all_matching || self.history {
+            let summaries =
+                select_all_pkgs(&self.context, src, &crate_.name, crate_.version.as_deref())?;
+            if self.history {
+                // The latest version is left checked out.
+                let latest = summaries.last().expect("at least one version is selected");
+                return Ok(vec![planned(latest, dest_path.to_owned())]);
+            }
+            return summaries
+                .iter()
+                .map(|summary| Ok(planned(summary, self.version_dir(dest_path, summary)?)))
+                .collect();
+        }
+
+        let summary = summary.expect("a version is selected unless cloning every matching version");
+        self.verify_index_checksum(crate_, &summary, src)?;
+        let mut plan = planned(&summary, dest_path.to_owned());
+        if self.use_git {
+            let pkg = download_pkg(&self.context, src, &summary)?;
+            let Some(repository) = pkg.manifest().metadata().repository.clone() else {
+                bail!(
+                    "Cannot clone {} from git repo because it is not specified in package's manifest.",
+                    summary.name()
+                );
+            };
+            if self.context.network_allowed() {
+                plan.commit = Some(git::remote_head(&repository)?);
+            }
+            plan.repository = Some(repository);
+        }
+        Ok(vec![plan])
     }
 
-    Ok(())
-}
+    // source_id returns the source to clone `crate_` from.
+    // A `.crate` archive is fetched and unpacked the first time it is needed, and then served
+    // as a path source.
+    fn source_id(&self, crate_: &Crate) -> CargoResult<SourceId> {
+        let source = &crate_.source.as_ref().unwrap_or(&self.source).cargo_source;
+        let CargoSource::CrateFile { location, sha256 } = source else {
+            return source.to_source_id(&self.context);
+        };
 
-/// Parses crate specifications like: crate, crate@x.y.z, crate@~23.4.5.
-pub fn parse_name_and_version(spec: &str) -> CargoResult<Crate> {
-    if !spec.contains('@') {
-        return Ok(Crate::new(spec.to_owned(), None));
+        let mut crate_files = self.crate_files.borrow_mut();
+        if let Some((_, unpacked)) = crate_files.iter().find(|(s, _)| s == source) {
+            return SourceId::for_path(&unpacked.root);
+        }
+        let unpacked = unpack_crate_file(&self.context, location, sha256.as_deref())?;
+        let srcid = SourceId::for_path(&unpacked.root)?;
+        crate_files.push((source.clone(), unpacked));
+        Ok(srcid)
     }
 
-    let mut parts = spec.split('@');
-    let crate_ = parts
-        .next()
-        .context(format!("Crate name missing in `{spec}`."))?;
-    let version = parts
-        .next()
-        .context(format!("Crate version missing in `{spec}`."))?;
+    // load_sources loads the sources of `crates`, once per source, and returns them with the
+    // index of the source of each crate.
+    // Without network access, it makes sure every crate can be cloned from the local cache
+    // before anything is cloned, and reports everything that is missing at once.
+    fn load_sources(&self, crates: &[&Crate]) -> CargoResult<(Sources<'_>, Vec<usize>)> {
+        let mut srcids = vec![];
+        let mut sources = vec![];
+        let mut source_of = Vec::with_capacity(crates.len());
+        for crate_ in crates {
+            let srcid = self.source_id(crate_)?;
+            let index = match srcids.iter().position(|id| *id == srcid) {
+                Some(index) => index,
+                None => {
+                    sources.push(get_source(&srcid, self.source_replacement, &self.context)?);
+                    srcids.push(srcid);
+                    sources.len() - 1
+                }
+            };
+            source_of.push(index);
+        }
 
-    Ok(Crate::new(
-        crate_.to_owned(),
-        Some(parse_version_req(version)?),
-    ))
-}
+        if self.context.network_allowed() {
+            return Ok((sources, source_of));
+        }
 
-#[cfg(test)]
-mod tests {
-    use std::{env, path::PathBuf};
+        let mut missing = vec![];
+        for (crate_, &index) in crates.iter().zip(&source_of) {
+            let src = &mut sources[index];
+            if let Err(e) = query_pkg(&self.context, src, &crate_.name, crate_.version.as_deref()) {
+                missing.push(format!("  - {e}"));
+            }
+        }
 
-    use super::*;
-    use tempfile::tempdir;
+        if !missing.is_empty() {
+            bail!(
+                "Unable to clone without network access, some packages are missing from the local cache:\n{}",
+                missing.join("\n")
+            );
+        }
 
-    #[test]
-    fn test_parse_version_req() {
-        assert_eq!("=12.4.5", parse_version_req("12.4.5").unwrap());
-        assert_eq!("=12.4.5", parse_version_req("=12.4.5").unwrap());
-        assert_eq!("12.2.*", parse_version_req("12.2.*").unwrap());
+        Ok((sources, source_of))
     }
 
-    #[test]
-    fn test_parse_version_req_invalid_req() {
-        assert_eq!(
-            "Invalid version requirement: `=foo`.",
-            parse_version_req("=foo").unwrap_err().to_string()
-        );
-    }
+    // clone_in clones the selected version of `crate_` into `dest_path`, or its history, which
+    // leaves the selected version checked out.
+    fn clone_in<'a, T>(
+        &self,
+        crate_: &Crate,
+        summary: Summary,
+        dest_path: &Path,
+        src: &mut T,
+    ) -> CargoResult<ClonedPackage>
+    where
+        T: Source + 'a,
+    {
+        if let Some(format) = self.archive {
+            let verified = self.verify_checksum(crate_, &summary, src)?;
+            return self.archive_summary(summary, dest_path, src, format, verified.as_ref());
+        }
 
-    #[test]
-    fn test_clone_directory() {
-        let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
-        let from = PathBuf::from(manifest_dir).join("tests/data");
-        let to = tempdir().unwrap();
-        let to_path = to.path();
+        self.prepare_destination(dest_path)?;
 
-        clone_directory(&from, to_path).unwrap();
+        if self.history {
+            return self.clone_history(crate_, dest_path, src);
+        }
 
-        assert!(to_path.join("Cargo.toml").exists());
-        assert!(!to_path.join("cargo-ok").exists());
+        let verified = self.verify_checksum(crate_, &summary, src)?;
+        let package = self.clone_summary(summary, dest_path, src, verified.as_ref())?;
+        if self.git_init {
+            commit_pristine(&package)?;
+        }
+        Ok(package)
     }
 
-    #[test]
-    fn test_clone_repo() {
-        let to = tempdir().unwrap();
-        let to_path = to.path();
+    // prepare_destination creates the destination directory, which must be empty if it exists.
+    fn prepare_destination(&self, dest_path: &Path) -> CargoResult<()> {
+        if !dest_path.exists() {
+            fs::create_dir_all(dest_path)?;
+        }
 
-        clone_git_repo("https://github.com/janlikar/cargo-clone", to_path).unwrap();
+        self.context
+            .shell()
+            .verbose(|s| s.note(format!("Cloning into {:?}", dest_path)))?;
 
-        assert!(to_path.exists());
-        assert!(to_path.join(".git").exists());
+        check_destination(dest_path)
     }
 
-    #[test]
-    fn test_parse_name_and_version() {
-        assert_eq!(
-            parse_name_and_version("foo").unwrap(),
-            Crate::new(String::from("foo"), None)
-        );
-        assert_eq!(
+    // clone_history creates a git repository with one commit per version of the package matching
+    // the requested version, oldest first. The latest version is left checked out.
+    fn clone_history<'a, T>(
+        &self,
+        crate_: &Crate,
+        dest_path: &Path,
+        src: &mut T,
+    ) -> CargoResult<ClonedPackage>
+    where
+        T: Source + 'a,
+    {
+        self.check_pinned_checksum(crate_)?;
+        let summaries =
+            select_all_pkgs(&self.context, src, &crate_.name, crate_.version.as_deref())?;
+
+        git::init(dest_path)?;
+
+        let mut latest = None;
+        for summary in summaries {
+            clear_worktree(dest_path)?;
+            let pubtime = summary.pubtime();
+            let package = self.clone_summary(summary, dest_path, src, None)?;
+
+            git::commit_all(dest_path, &commit_message(&package), pubtime)?;
+            git::tag(dest_path, &format!("v{}", package.version))?;
+            latest = Some(package);
+        }
+
+        Ok(latest.expect("at least one version is selected"))
+    }
+
+    // clone_all_matching clones every version of the package matching the requested version into
+    // subdirectories of `directory` named after the directory template, or `name-version`,
+    // oldest first.
+    fn clone_all_matching<'a, T>(
+        &self,
+        crate_: &Crate,
+        directory: &Path,
+        src: &mut T,
+    ) -> CargoResult<Vec<ClonedPackage>>
+    where
+        T: Source + 'a,
+    {
+        self.check_pinned_checksum(crate_)?;
+        let summaries =
+            select_all_pkgs(&self.context, src, &crate_.name, crate_.version.as_deref())?;
+
+        summaries
+            .into_iter()
+            .map(|summary| {
+                let dest_path = self.version_dir(directory, &summary)?;
+                if let Some(format) = self.archive {
+                    return self.archive_summary(summary, &dest_path, src, format, None);
+                }
+                self.prepare_destination(&dest_path)?;
+                let package = self.clone_summary(summary, &dest_path, src, None)?;
+                if self.git_init {
+                    commit_pristine(&package)?;
+                }
+                Ok(package)
+            })
+            .collect()
+    }
+
+    fn clone_single<'a, T>(
+        &self,
+        crate_: &Crate,
+        dest_path: &Path,
+        src: &mut T,
+    ) -> CargoResult<ClonedPackage>
+    where
+        T: Source + 'a,
+    {
+        let summary = self.select(crate_, src)?;
+        let verified = self.verify_checksum(crate_, &summary, src)?;
+        self.clone_summary(summary, dest_path, src, verified.as_ref())
+    }
+
+    // check_pinned_checksum fails if `crate_` has a pinned checksum which cannot be verified by
+    // the clone: only a single version cloned from its `.crate` archive can be verified.
+    fn check_pinned_checksum(&self, crate_: &Crate) -> CargoResult<()> {
+        if crate_.checksum.is_none() {
+            return Ok(());
+        }
+        if self.all_matching || self.history {
+            bail!("A checksum can only be pinned when cloning a single version.");
+        }
+        if self.use_git {
+            bail!(
+                "Cannot pin the checksum of {}, since git repositories are not cloned from the `.crate` archive.",
+                crate_.name
+            );
+        }
+        Ok(())
+    }
+
+    // verify_checksum makes sure the package has the checksum pinned or locked for `crate_`, if
+    // any, according to its source and to the downloaded `.crate` archive. The verified archive
+    // is returned unpacked, as the sources cargo extracted from it may have changed since: they
+    // must not be read instead.
+    fn verify_checksum<'a, T>(
+        &self,
+        crate_: &Crate,
+        summary: &Summary,
+        src: &mut T,
+    ) -> CargoResult<Option<UnpackedCrate>>
+    where
+        T: Source + 'a,
+    {
+        self.verify_index_checksum(crate_, summary, src)?;
+        let Some(expected) = self.expected_checksum(crate_, src.source_id()) else {
+            return Ok(None);
+        };
+
+        let archive = cache::cached_crate_path(
+            &self.context,
+            src.replaced_source_id(),
+            summary.package_id(),
+        );
+        // Otherwise, cargo verifies the archive against the checksum recorded by the source.
+        if let Some(archive) = archive {
+            if !archive.exists() {
+                download_pkg(&self.context, src, summary)?;
+            }
+            let data = fs::read(&archive)
+                .with_context(|| format!("Unable to read `{}`.", archive.display()))?;
+            crate_file::verify_checksum(&data, expected).with_context(|| {
+                format!("Cannot clone {} {}.", summary.name(), summary.version())
+            })?;
+            let location = archive.display().to_string();
+            return unpack_crate_data(&data, &location).map(Some);
+        }
+        Ok(None)
+    }
+
+    // verify_index_checksum makes sure the source of the package records the checksum pinned or
+    // locked for `crate_`, if any, without downloading the package. A source which does not
+    // record checksums is only accepted if the archive can be verified once downloaded.
+    fn verify_index_checksum<'a, T>(
+        &self,
+        crate_: &Crate,
+        summary: &Summary,
+        src: &mut T,
+    ) -> CargoResult<()>
+    where
+        T: Source + 'a,
+    {
+        self.check_pinned_checksum(crate_)?;
+        let Some(expected) = self.expected_checksum(crate_, src.source_id()) else {
+            return Ok(());
+        };
+
+        match summary.checksum() {
+            Some(actual) if actual != expected => Err(anyhow::anyhow!(
+                "Checksum mismatch: expected sha256 `{expected}`, {} records `{actual}`.",
+                src.source_id()
+            ))
+            .with_context(|| format!("Cannot clone {} {}.", summary.name(), summary.version())),
+            Some(_) => Ok(()),
+            None if cache::cached_crate_path(
+                &self.context,
+                src.replaced_source_id(),
+                summary.package_id(),
+            )
+            .is_none() =>
+            {
+                bail!(
+                    "Cannot verify the checksum of {} {}, {} does not record checksums.",
+                    summary.name(),
+                    summary.version(),
+                    src.source_id()
+                )
+            }
+            None => Ok(()),
+        }
+    }
+
+    // expected_checksum returns the checksum the archive of `crate_` from `srcid` must have: its
+    // pinned checksum, or else the checksum recorded in the lock.
+    fn expected_checksum<'s>(&'s self, crate_: &'s Crate, srcid: SourceId) -> Option<&'s str> {
+        crate_.checksum.as_deref().or_else(|| {
+            let (_, checksum) = self.lock.as_ref()?.get(crate_, srcid)?;
+            Some(checksum)
+        })
+    }
+
+    // select selects the version of `crate_` to clone, which is the locked version if a lock
+    // is set. The checksum of a locked version must not have changed.
+    fn select<'a, T>(&self, crate_: &Crate, src: &mut T) -> CargoResult<Summary>
+    where
+        T: Source + 'a,
+    {
+        let Some(lock) = &self.lock else {
+            return select_pkg(
+                &self.context,
+                src,
+                &crate_.name,
+                crate_.version.as_deref(),
+                self.version_selection,
+            );
+        };
+
+        let Some((version, checksum)) = lock.get(crate_, src.source_id()) else {
+            bail!(
+                "Package `{}@{}` from {} is not locked.",
+                crate_.name,
+                crate_.version.as_deref().unwrap_or("*"),
+                src.source_id()
+            );
+        };
+        let summary = select_pkg(
+            &self.context,
+            src,
+            &crate_.name,
+            Some(&format!("={version}")),
+            VersionSelection::Newest,
+        )?;
+        if summary.checksum() != Some(checksum) {
+            bail!(
+                "The checksum of {} {} does not match the lock: expected {}, found {}.",
+                crate_.name,
+                version,
+                checksum,
+                summary.checksum().unwrap_or("none")
+            );
+        }
+        Ok(summary)
+    }
+
+    fn clone_summary<'a, T>(
+        &self,
+        summary: Summary,
+        dest_path: &Path,
+        src: &mut T,
+        verified: Option<&UnpackedCrate>,
+    ) -> CargoResult<ClonedPackage>
+    where
+        T: Source + 'a,
+    {
+        let extracted = cache::extracted_crate_path(
+            &self.context,
+            src.replaced_source_id(),
+            summary.package_id(),
+        );
+        let context = || format!("Cannot clone {} {}.", summary.name(), summary.version());
+
+        let stats = if self.use_git {
+            let pkg = download_pkg(&self.context, src, &summary)?;
+            let repo = &pkg.manifest().metadata().repository;
+
+            if repo.is_none() {
+                bail!(
+                    "Cannot clone {} from git repo because it is not specified in package's manifest.",
+                    summary.name()
+                )
+            }
+
+            clone_git_repo(repo.as_ref().unwrap(), dest_path)?;
+            CopyStats::default()
+        } else if let Some(verified) = verified {
+            clone_directory(&self.context, &verified.root, dest_path, &self.copy_options)
+                .with_context(context)?
+        } else if let Some(extracted) = extracted {
+            // The package has already been extracted by cargo, so there is nothing to download.
+            self.context.shell().note(format!(
+                "Using {} {} from the local cache",
+                summary.name(),
+                summary.version()
+            ))?;
+            clone_directory(&self.context, &extracted, dest_path, &self.copy_options)
+                .with_context(context)?
+        } else {
+            let pkg = download_pkg(&self.context, src, &summary)?;
+            clone_directory(&self.context, pkg.root(), dest_path, &self.copy_options)
+                .with_context(context)?
+        };
+
+        if stats.skipped_files > 0 {
+            self.context.shell().note(format!(
+                "Skipped {} files ({:.1}) of {} {}",
+                stats.skipped_files,
+                HumanBytes(stats.skipped_bytes),
+                summary.name(),
+                summary.version()
+            ))?;
+        }
+
+        Ok(ClonedPackage {
+            name: summary.name().to_string(),
+            version: summary.version().clone(),
+            source_id: src.source_id(),
+            replaced_source_id: src.replaced_source_id(),
+            checksum: summary.checksum().map(str::to_owned),
+            path: dest_path.to_owned(),
+            skipped_files: stats.skipped_files,
+            skipped_bytes: stats.skipped_bytes,
+        })
+    }
+
+    // archive_summary writes the package as an archive at the path of `dest_path` with the
+    // extension of `format`. The package is cloned into a temporary directory first, so the
+    // file filters apply, except for pristine `.crate` archives.
+    fn archive_summary<'a, T>(
+        &self,
+        summary: Summary,
+        dest_path: &Path,
+        src: &mut T,
+        format: ArchiveFormat,
+        verified: Option<&UnpackedCrate>,
+    ) -> CargoResult<ClonedPackage>
+    where
+        T: Source + 'a,
+    {
+        let path = format.path(dest_path);
+        check_destination(&path)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        self.context
+            .shell()
+            .verbose(|s| s.note(format!("Archiving into {:?}", path)))?;
+
+        if format == ArchiveFormat::Crate {
+            let Some(archive) = cache::cached_crate_path(
+                &self.context,
+                src.replaced_source_id(),
+                summary.package_id(),
+            ) else {
+                bail!(
+                    "Cannot copy the `.crate` archive of {} {}, {} does not serve archives.",
+                    summary.name(),
+                    summary.version(),
+                    src.replaced_source_id()
+                );
+            };
+            if !archive.exists() {
+                download_pkg(&self.context, src, &summary)?;
+            }
+            fs::copy(&archive, &path).with_context(|| {
+                format!(
+                    "Unable to copy `{}` to `{}`.",
+                    archive.display(),
+                    path.display()
+                )
+            })?;
+
+            return Ok(ClonedPackage {
+                name: summary.name().to_string(),
+                version: summary.version().clone(),
+                source_id: src.source_id(),
+                replaced_source_id: src.replaced_source_id(),
+                checksum: summary.checksum().map(str::to_owned),
+                path,
+                skipped_files: 0,
+                skipped_bytes: 0,
+            });
+        }
+
+        let tmp = TempDir::new().context("Unable to create a temporary directory.")?;
+        let prefix = format!("{}-{}", summary.name(), summary.version());
+        let root = tmp.path().join(&prefix);
+        fs::create_dir(&root)?;
+        let package = self.clone_summary(summary, &root, src, verified)?;
+        archive::write_archive(&root, &prefix, &path, format)?;
+
+        Ok(ClonedPackage { path, ..package })
+    }
+}
+
+// check_destination checks that a package can be cloned into `dest_path`.
+// Cloning into an existing directory is only allowed if the directory is empty.
+fn check_destination(dest_path: &Path) -> CargoResult<()> {
+    if dest_path.exists() && !dest_path.is_dir() {
+        bail!("destination path '{}' already exists.", dest_path.display());
+    }
+    if dest_path.exists() && dest_path.read_dir()?.next().is_some() {
+        bail!(
+            "destination path '{}' already exists and is not an empty directory.",
+            dest_path.display()
+        );
+    }
+    Ok(())
+}
+
+// check_destinations reports every planned package which cannot be cloned into its destination
+// at once.
+fn check_destinations(planned: &[PlannedClone]) -> CargoResult<()> {
+    let mut conflicts = vec![];
+    for (i, package) in planned.iter().enumerate() {
+        if let Some(other) = planned[..i].iter().find(|other| other.path == package.path) {
+            conflicts.push(format!(
+                "  - {} {} and {} {} would both be cloned into '{}'.",
+                other.name,
+                other.version,
+                package.name,
+                package.version,
+                package.path.display()
+            ));
+        } else if let Err(e) = check_destination(&package.path) {
+            conflicts.push(format!("  - {e}"));
+        }
+    }
+
+    if !conflicts.is_empty() {
+        bail!(
+            "Some packages cannot be cloned into their destination:\n{}",
+            conflicts.join("\n")
+        );
+    }
+    Ok(())
+}
+
+// commit_message describes the commit of the pristine sources of a package.
+fn commit_message(package: &ClonedPackage) -> String {
+    let mut message = format!(
+        "{} {}\n\nSource: {}",
+        package.name,
+        package.version,
+        package.source_id.as_url()
+    );
+    if package.replaced_source_id != package.source_id {
+        message.push_str(&format!(
+            "\nReplaced-Source: {}",
+            package.replaced_source_id.as_url()
+        ));
+    }
+    message
+}
+
+// commit_pristine commits the pristine sources of a package into the git repository of its
+// clone, which is initialized if needed.
+fn commit_pristine(package: &ClonedPackage) -> CargoResult<()> {
+    git::init(&package.path)?;
+    git::commit_all(&package.path, &commit_message(package), None)
+}
+
+// record_pristine saves the record of a package cloned by `Cloner::sync`, and commits the
+// package with its record if `git_init` is true.
+fn record_pristine(package: &ClonedPackage, git_init: bool) -> CargoResult<()> {
+    CloneRecord::new(package)?.save(&package.path)?;
+    if git_init {
+        commit_pristine(package)?;
+    }
+    Ok(())
+}
+
+// check_pristine fails unless the clone of `name` in `dir` can be replaced without losing local
+// changes. It must have a record of its pristine sources, and both the record and its manifest
+// must name the package, so that no other directory is cleared. Then its git repository must
+// have no uncommitted changes with `git_init`, and otherwise its files must not have changed
+// since it was cloned, as recorded in `record`.
+fn check_pristine(
+    context: &GlobalContext,
+    dir: &Path,
+    name: &str,
+    record: Option<&CloneRecord>,
+    git_init: bool,
+) -> CargoResult<()> {
+    let Some(record) = record else {
+        bail!(
+            "`{}` has no record of its pristine sources, so local changes cannot be detected. Remove it to clone it again.",
+            dir.display()
+        );
+    };
+    let cloned = read_package(context, dir)?;
+    if record.name != name || cloned.name().as_str() != name {
+        bail!(
+            "`{}` is not a clone of {name}, it has `{}` instead. Remove it or change the path of {name}.",
+            dir.display(),
+            cloned.name()
+        );
+    }
+
+    if git_init && dir.join(".git").exists() {
+        if !git::status(dir)?.is_empty() {
+            bail!(
+                "`{}` has uncommitted changes, commit or discard them before updating it.",
+                dir.display()
+            );
+        }
+        return Ok(());
+    }
+
+    let changed = record.changed_files(dir)?;
+    if !changed.is_empty() {
+        bail!(
+            "`{}` has local changes to `{}`, which would be lost by updating it. Move them out of the clone, or use the `git-init` mode to keep them in git.",
+            dir.display(),
+            changed.join("`, `")
+        );
+    }
+    Ok(())
+}
+
+// clear_worktree removes everything from `dir`, except the git repository.
+fn clear_worktree(dir: &Path) -> CargoResult<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name() == ".git" {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+// local_package identifies the package in `dir` by the name and version in its manifest.
+fn local_package(context: &GlobalContext, dir: &Path) -> CargoResult<Crate> {
+    let pkg = read_package(context, dir)?;
+    Ok(Crate::new(
+        pkg.name().to_string(),
+        Some(format!("={}", pkg.version())),
+    ))
+}
+
+fn read_package(context: &GlobalContext, dir: &Path) -> CargoResult<Package> {
+    let srcid = SourceId::for_path(dir)?;
+    PathSource::new(dir, srcid, context)
+        .root_package()
+        .with_context(|| format!("Unable to read the package in `{}`.", dir.display()))
+}
+
+fn get_source<'a>(
+    srcid: &SourceId,
+    source_replacement: SourceReplacement,
+    context: &'a GlobalContext,
+) -> CargoResult<Box<dyn Source + 'a>> {
+    let mut source = if srcid.is_path() {
+        let path = srcid.url().to_file_path().expect("path must be valid");
+        Box::new(PathSource::new(&path, *srcid, context))
+    } else {
+        let map = match source_replacement {
+            SourceReplacement::Ignore => SourceConfigMap::empty(context)?,
+            SourceReplacement::Allow | SourceReplacement::Require => SourceConfigMap::new(context)?,
+        };
+        map.load(*srcid, &Default::default())?
+    };
+
+    if source_replacement == SourceReplacement::Require && !source.is_replaced() {
+        bail!(
+            "Source replacement is required, but {} is not replaced in cargo's configuration.",
+            srcid
+        );
+    }
+
+    source.invalidate_cache();
+    Ok(source)
+}
+
+// query_pkg returns the available versions of the package which match `vers`.
+// Without network access, only packages which have already been downloaded are available.
+fn query_pkg<'a, T>(
+    context: &GlobalContext,
+    src: &mut T,
+    name: &str,
+    vers: Option<&str>,
+) -> CargoResult<Vec<Summary>>
+where
+    T: Source + 'a,
+{
+    let dep = Dependency::parse(name, vers, src.source_id())?;
+    let mut summaries = vec![];
+
+    loop {
+        match src.query(&dep, QueryKind::Exact, &mut |summary| {
+            summaries.push(summary)
+        })? {
+            std::task::Poll::Ready(()) => break,
+            std::task::Poll::Pending => src.block_until_ready()?,
+        }
+    }
+
+    let candidates: Vec<Summary> = summaries
+        .into_iter()
+        .filter_map(|idxs| match idxs {
+            IndexSummary::Candidate(s) => Some(s),
+            _ => None,
+        })
+        .collect();
+
+    if context.network_allowed() {
+        return Ok(candidates);
+    }
+
+    if candidates.is_empty() {
+        bail!(
+            "Package `{}@{}` not found in the cached index of {}",
+            name,
+            vers.unwrap_or("*.*.*"),
+            src.describe()
+        );
+    }
+
+    let srcid = src.replaced_source_id();
+    let (downloaded, missing): (Vec<_>, Vec<_>) = candidates
+        .into_iter()
+        .partition(|s| cache::is_crate_downloaded(context, srcid, s.package_id()));
+
+    if downloaded.is_empty() {
+        let versions: Vec<_> = missing.iter().map(|s| s.version().to_string()).collect();
+        bail!(
+            "Package `{}@{}` has not been downloaded yet (missing versions: {})",
+            name,
+            vers.unwrap_or("*.*.*"),
+            versions.join(", ")
+        );
+    }
+
+    Ok(downloaded)
+}
+
+fn select_pkg<'a, T>(
+    context: &GlobalContext,
+    src: &mut T,
+    name: &str,
+    vers: Option<&str>,
+    selection: VersionSelection,
+) -> CargoResult<Summary>
+where
+    T: Source + 'a,
+{
+    if selection == VersionSelection::ExactOnly && !vers.is_some_and(is_exact_version_req) {
+        bail!(
+            "Package `{}` must be requested with an exact version, e.g. `{}@1.2.3`.",
+            name,
+            name
+        );
+    }
+
+    let summaries = query_pkg(context, src, name, vers)?;
+    let selected = match selection {
+        VersionSelection::Newest | VersionSelection::ExactOnly => {
+            summaries.into_iter().max_by_key(|s| s.version().clone())
+        }
+        VersionSelection::Oldest => summaries.into_iter().min_by_key(|s| s.version().clone()),
+    };
+
+    match selected {
+        Some(l) => {
+            context.shell().verbose(|s| {
+                s.note(format!(
+                    "{} {} is served by {}",
+                    name,
+                    l.version(),
+                    src.describe()
+                ))
+            })?;
+            Ok(l)
+        }
+        None => bail!("Package `{}@{}` not found", name, vers.unwrap_or("*.*.*")),
+    }
+}
+
+// select_all_pkgs returns every version of the package which matches `vers`, oldest first.
+fn select_all_pkgs<'a, T>(
+    context: &GlobalContext,
+    src: &mut T,
+    name: &str,
+    vers: Option<&str>,
+) -> CargoResult<Vec<Summary>>
+where
+    T: Source + 'a,
+{
+    let mut summaries = query_pkg(context, src, name, vers)?;
+    if summaries.is_empty() {
+        bail!("Package `{}@{}` not found", name, vers.unwrap_or("*.*.*"));
+    }
+    summaries.sort_by(|a, b| a.version().cmp(b.version()));
+    Ok(summaries)
+}
+
+fn download_pkg<'a, T>(
+    context: &GlobalContext,
+    src: &mut T,
+    summary: &Summary,
+) -> CargoResult<Package>
+where
+    T: Source + 'a,
+{
+    context.shell().note(format!(
+        "Downloading {} {}",
+        summary.name(),
+        summary.version()
+    ))?;
+    let pkg = Box::new(&mut *src).download_now(summary.package_id(), context)?;
+    // Directory sources check the files against `.cargo-checksum.json` here.
+    src.verify(summary.package_id())?;
+    Ok(pkg)
+}
+
+// is_exact_version_req returns whether `req` only matches a single version.
+fn is_exact_version_req(req: &str) -> bool {
+    match VersionReq::parse(req) {
+        Ok(req) => match req.comparators.as_slice() {
+            [c] => c.op == semver::Op::Exact && c.minor.is_some() && c.patch.is_some(),
+            _ => false,
+        },
+        Err(_) => false,
+    }
+}
+
+pub(crate) fn parse_version_req(version: &str) -> CargoResult<String> {
+    // This function's main purpose is to treat "x.y.z" as "=x.y.z"
+    // so specifying the version in CLI works as expected.
+    let first = version.chars().next();
+
+    if first.is_none() {
+        bail!("Version cannot be empty.")
+    };
+
+    let is_req = "<>=^~".contains(first.unwrap()) || version.contains('*');
+
+    if is_req {
+        let vers = VersionReq::parse(version)
+            .with_context(|| format!("Invalid version requirement: `{version}`."))?;
+        Ok(vers.to_string())
+    } else {
+        Ok(format!("={version}"))
+    }
+}
+
+// Files that cargo places next to the package sources, which are not part of the package.
+const IGNORED_FILES: &[&str] = &[".cargo-ok", ".cargo-checksum.json"];
+
+/// Options controlling how [`clone_directory`] copies a package.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct CopyOptions {
+    /// Hard link files instead of copying them, falling back to copying where linking is not
+    /// possible (e.g. across file systems).
+    pub(crate) link: bool,
+    /// Copy the targets of symbolic links instead of recreating the links.
+    pub(crate) follow_symlinks: bool,
+    /// Skip files and directories which cannot be read, with a warning, instead of failing.
+    pub(crate) skip_unreadable: bool,
+    /// Selects which files are copied.
+    pub(crate) filter: FileFilter,
+    /// If set, only this directory or file of the package is copied, relative to its root.
+    pub(crate) subpath: Option<PathBuf>,
+}
+
+/// Files which were not copied by [`clone_directory`] because of the file filters.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct CopyStats {
+    pub(crate) skipped_files: u64,
+    pub(crate) skipped_bytes: u64,
+}
+
+// subpath_root returns the directory or file of the package in `from` which is copied, which
+// is the package itself unless a subpath is set.
+pub(crate) fn subpath_root(from: &Path, options: &CopyOptions) -> CargoResult<PathBuf> {
+    let Some(subpath) = &options.subpath else {
+        return Ok(from.to_owned());
+    };
+    let root = from.join(subpath);
+    if fs::symlink_metadata(&root).is_err() {
+        bail!("`{}` does not exist in the package.", subpath.display());
+    }
+    if !fs::canonicalize(&root)?.starts_with(fs::canonicalize(from)?) {
+        bail!("`{}` is outside of the package.", subpath.display());
+    }
+    Ok(root)
+}
+
+// clone_directory copies the contents of one directory into another directory, which must
+// already exist. Symbolic links, permissions and modification times are preserved.
+// With a subpath, only the contents of that directory of `from` are copied, or that file, while
+// the filters still match paths relative to `from`.
+fn clone_directory(
+    context: &GlobalContext,
+    from: &Path,
+    to: &Path,
+    options: &CopyOptions,
+) -> CargoResult<CopyStats> {
+    if !to.is_dir() {
+        bail!("Not a directory: {}", to.to_string_lossy());
+    }
+
+    let root = subpath_root(from, options)?;
+
+    // Permissions and times of directories are restored after their contents have been copied,
+    // so that read-only directories can be filled and their times are not updated afterwards.
+    let mut dirs = vec![];
+    let mut stats = CopyStats::default();
+
+    for entry in WalkDir::new(&root).follow_links(options.follow_symlinks) {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                let path = err.path().unwrap_or(from).to_owned();
+                if options.skip_unreadable {
+                    let reason = match err.io_error() {
+                        Some(io_err) => io_err.to_string(),
+                        None => err.to_string(),
+                    };
+                    context
+                        .shell()
+                        .warn(format!("Skipping `{}`: {}", path.display(), reason))?;
+                    continue;
+                }
+                return Err(err).with_context(|| format!("Unable to read `{}`.", path.display()));
+            }
+        };
+
+        // The root is copied into the destination itself, unless it is a single file.
+        if entry.depth() == 0 && entry.file_type().is_dir() {
+            continue;
+        }
+
+        let relative = entry.path().strip_prefix(from).with_context(|| {
+            format!(
+                "`{}` is not inside `{}`.",
+                entry.path().display(),
+                from.display()
+            )
+        })?;
+        if IGNORED_FILES.iter().any(|f| relative == Path::new(f)) {
+            continue;
+        }
+        let dest_path = match entry.path().strip_prefix(&root)? {
+            path if path.as_os_str().is_empty() => to.join(entry.file_name()),
+            path => to.join(path),
+        };
+        let is_dir = entry.file_type().is_dir();
+
+        // Directories which are not included themselves may still contain included files.
+        if options.filter.is_excluded(relative)
+            || (!is_dir && !options.filter.is_included(relative))
+        {
+            if !is_dir {
+                stats.skipped_files += 1;
+                stats.skipped_bytes += entry.metadata().map(|m| m.len()).unwrap_or(0);
+            }
+            continue;
+        }
+
+        if options.skip_unreadable
+            && entry.file_type().is_file()
+            && let Err(err) = fs::File::open(entry.path())
+        {
+            context
+                .shell()
+                .warn(format!("Skipping `{}`: {}", entry.path().display(), err))?;
+            continue;
+        }
+
+        let copied = copy_entry(context, &entry, &dest_path, options).with_context(|| {
+            format!(
+                "Unable to copy `{}` to `{}`.",
+                entry.path().display(),
+                dest_path.display()
+            )
+        })?;
+        if let Some(metadata) = copied {
+            let prune_if_empty = !options.filter.is_included(relative);
+            dirs.push((dest_path, metadata, prune_if_empty));
+        }
+    }
+
+    for (path, metadata, prune_if_empty) in dirs.iter().rev() {
+        if *prune_if_empty && path.read_dir()?.next().is_none() {
+            fs::remove_dir(path)?;
+            continue;
+        }
+        restore_metadata(path, metadata)
+            .with_context(|| format!("Unable to set permissions of `{}`.", path.display()))?;
+    }
+
+    Ok(stats)
+}
+
+// copy_entry copies a single entry found by clone_directory.
+// For directories, it returns the metadata which has to be restored once their contents
+// have been copied.
+fn copy_entry(
+    context: &GlobalContext,
+    entry: &DirEntry,
+    dest_path: &Path,
+    options: &CopyOptions,
+) -> CargoResult<Option<fs::Metadata>> {
+    let file_type = entry.file_type();
+
+    if file_type.is_dir() {
+        fs::create_dir(dest_path)?;
+        return Ok(Some(entry.metadata()?));
+    }
+
+    if file_type.is_symlink() {
+        let metadata = entry.metadata()?;
+        symlink(&fs::read_link(entry.path())?, entry.path(), dest_path)?;
+        filetime::set_symlink_file_times(
+            dest_path,
+            FileTime::from_last_access_time(&metadata),
+            FileTime::from_last_modification_time(&metadata),
+        )?;
+    } else if file_type.is_file() {
+        if options.link && fs::hard_link(entry.path(), dest_path).is_ok() {
+            return Ok(None);
+        }
+        let metadata = entry.metadata()?;
+        // This also copies the permissions.
+        fs::copy(entry.path(), dest_path)?;
+        filetime::set_file_times(
+            dest_path,
+            FileTime::from_last_access_time(&metadata),
+            FileTime::from_last_modification_time(&metadata),
+        )?;
+    } else {
+        context.shell().warn(format!(
+            "Skipping `{}`: unsupported file type.",
+            entry.path().display()
+        ))?;
+    }
+
+    Ok(None)
+}
+
+fn restore_metadata(path: &Path, metadata: &fs::Metadata) -> io::Result<()> {
+    fs::set_permissions(path, metadata.permissions())?;
+    filetime::set_file_times(
+        path,
+        FileTime::from_last_access_time(metadata),
+        FileTime::from_last_modification_time(metadata),
+    )
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, _original: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn symlink(target: &Path, original: &Path, link: &Path) -> io::Result<()> {
+    // Windows distinguishes between links to files and to directories.
+    if fs::metadata(original).is_ok_and(|m| m.is_dir()) {
+        std::os::windows::fs::symlink_dir(target, link)
+    } else {
+        std::os::windows::fs::symlink_file(target, link)
+    }
+}
+
+fn clone_git_repo(repo: &str, to: &Path) -> CargoResult<()> {
+    let status = Command::new("git")
+        .arg("clone")
+        .arg(repo)
+        .arg(to.to_str().unwrap())
+        .status()
+        .context("Failed to clone from git repo.")?;
+
+    if !status.success() {
+        bail!("Failed to clone from git repo.")
+    }
+
+    Ok(())
+}
+
+/// Parses crate specifications like: crate, crate@x.y.z, crate@~23.4.5.
+///
+/// A destination can be appended after `=`, like `crate@x.y.z=vendor/crate-fork`, and the
+/// SHA-256 checksum of the `.crate` archive can be pinned after the version, like
+/// `crate@x.y.z#sha256=…`.
+///
+/// The crate can be prefixed with the name of a registry from cargo's configuration, like
+/// `my-registry/crate@x.y.z`, or be a package ID spec with the URL of a registry index, like
+/// `registry+https://github.com/rust-lang/crates.io-index#crate@x.y.z`.
+pub fn parse_name_and_version(spec: &str) -> CargoResult<Crate> {
+    let (spec, checksum) = split_checksum(spec)?;
+    let (spec, destination) = split_destination(&spec);
+    let mut crate_ = parse_crate(spec)?;
+    if let Some(checksum) = checksum {
+        crate_ = crate_.with_checksum(checksum);
+    }
+    match destination {
+        Some("") => bail!("Destination missing in `{spec}=`."),
+        Some(destination) => {
+            check_destination_dir(Path::new(destination))?;
+            Ok(crate_.with_destination(destination))
+        }
+        None => Ok(crate_),
+    }
+}
+
+// split_checksum removes a checksum like `#sha256=…` from a crate spec. It follows the version
+// and may be followed by a destination.
+fn split_checksum(spec: &str) -> CargoResult<(String, Option<&str>)> {
+    let Some((crate_, rest)) = spec.split_once("#sha256=") else {
+        return Ok((spec.to_owned(), None));
+    };
+    let (checksum, destination) = match rest.split_once('=') {
+        Some((checksum, destination)) => (checksum, Some(destination)),
+        None => (rest, None),
+    };
+    if !is_valid_checksum(checksum) {
+        bail!("Invalid checksum in `{spec}`, expected 64 hexadecimal digits after `#sha256=`.");
+    }
+
+    let spec = match destination {
+        Some(destination) => format!("{crate_}={destination}"),
+        None => crate_.to_owned(),
+    };
+    Ok((spec, Some(checksum)))
+}
+
+/// Returns true if `checksum` is a SHA-256 checksum of 64 hexadecimal digits, as expected by
+/// [`Crate::with_checksum`].
+pub fn is_valid_checksum(checksum: &str) -> bool {
+    checksum.len() == 64 && checksum.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Parses a list of crate specifications, one per line, like in [`parse_name_and_version`].
+///
+/// Empty lines are ignored, and `#` starts a comment at the start of a line or after a space.
+pub fn parse_crate_list(list: &str) -> CargoResult<Vec<Crate>> {
+    list.lines()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            let spec = match line.find(" #").or_else(|| line.find("\t#")) {
+                Some(comment) => &line[..comment],
+                None => line,
+            };
+            let spec = spec.trim();
+            if spec.is_empty() || spec.starts_with('#') {
+                return None;
+            }
+            Some(
+                parse_name_and_version(spec)
+                    .with_context(|| format!("Invalid crate spec on line {}", i + 1)),
+            )
+        })
+        .collect()
+}
+
+// split_destination splits the destination from a crate spec like `foo@1.0=vendor/foo`.
+// The `=` of version requirements like `foo@=1.0` or `foo@>=1.0` follows `@`, an operator or
+// a space, while the separator follows a name or a version.
+fn split_destination(spec: &str) -> (&str, Option<&str>) {
+    // The URL of a package ID spec ends at `#`.
+    let start = match spec.split_once("://") {
+        Some(_) => spec.find('#').map_or(spec.len(), |i| i + 1),
+        None => 0,
+    };
+    let separator = spec[start..].match_indices('=').find(|(i, _)| {
+        spec[start..start + i]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '*'))
+    });
+
+    match separator {
+        Some((i, _)) => (&spec[..start + i], Some(&spec[start + i + 1..])),
+        None => (spec, None),
+    }
+}
+
+// parse_crate parses a crate spec without destination.
+fn parse_crate(spec: &str) -> CargoResult<Crate> {
+    if spec.contains("://") {
+        return parse_package_id_spec(spec);
+    }
+
+    let (registry, name_and_version) = match spec.split_once('/') {
+        Some((registry, rest)) if !registry.is_empty() && !rest.contains('/') => {
+            (Some(registry), rest)
+        }
+        Some(_) => bail!("Invalid crate spec `{spec}`, expected `registry/crate@version`."),
+        None => (None, spec),
+    };
+    let (name, version) = match name_and_version.split_once('@') {
+        Some((name, version)) => (name, Some(version)),
+        None => (name_and_version, None),
+    };
+
+    if name.is_empty() {
+        bail!("Crate name missing in `{spec}`.");
+    }
+    validate_name(name).with_context(|| format!("Invalid crate name in `{spec}`."))?;
+    let version = match version {
+        Some("") => bail!("Crate version missing in `{spec}`."),
+        Some(version) if version.contains('@') => {
+            bail!("Invalid crate spec `{spec}`, `@` can only separate the crate from its version.")
+        }
+        Some(version) => Some(parse_version_req(version)?),
+        None => None,
+    };
+
+    let crate_ = Crate::new(name.to_owned(), version);
+    Ok(match registry {
+        Some(registry) => crate_.with_source(ClonerSource::registry(registry)),
+        None => crate_,
+    })
+}
+
+// parse_package_id_spec parses a package ID spec with the URL of a registry index.
+fn parse_package_id_spec(spec: &str) -> CargoResult<Crate> {
+    let (_, fragment) = spec
+        .split_once('#')
+        .with_context(|| format!("Crate name missing in `{spec}`, e.g. `{spec}#crate@1.2.3`."))?;
+    if fragment.is_empty() || fragment.starts_with('@') {
+        bail!("Crate name missing in `{spec}`.");
+    }
+    if fragment.ends_with('@') {
+        bail!("Crate version missing in `{spec}`.");
+    }
+
+    let id = PackageIdSpec::parse(spec).with_context(|| format!("Invalid crate spec `{spec}`."))?;
+    let url = id.url().expect("specs with a URL have a URL").clone();
+    let source = match id.kind() {
+        None | Some(SourceKind::Registry) | Some(SourceKind::SparseRegistry)
+            if url.as_str() == CRATES_IO_INDEX =>
+        {
+            ClonerSource::crates_io()
+        }
+        // The URL of sparse registries keeps its `sparse+` prefix.
+        None | Some(SourceKind::Registry) | Some(SourceKind::SparseRegistry) => {
+            ClonerSource::index_from_url(url)
+        }
+        Some(SourceKind::Git(_)) => bail!(
+            "Cannot clone from the git repository in `{spec}`, use `--git` to clone the repository of a crate."
+        ),
+        Some(_) => bail!("Invalid crate spec `{spec}`, only registry sources are supported."),
+    };
+    let version = id
+        .partial_version()
+        .map(|version| parse_version_req(&version.to_string()))
+        .transpose()?;
+
+    Ok(Crate::new(id.name().to_owned(), version).with_source(source))
+}
+
+// render_dir_template replaces the `{name}` and `{version}` placeholders of a directory template.
+pub(crate) fn render_dir_template(
+    template: &str,
+    name: &str,
+    version: &str,
+) -> CargoResult<String> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('}').map(|i| start + i + 1) else {
+            bail!("Unclosed `{{` in directory template `{template}`.");
+        };
+        match &rest[start..end] {
+            "{name}" => rendered.push_str(name),
+            "{version}" => rendered.push_str(version),
+            placeholder => bail!(
+                "Unknown placeholder `{placeholder}` in directory template `{template}`, expected `{{name}}` or `{{version}}`."
+            ),
+        }
+        rest = &rest[end..];
+    }
+    rendered.push_str(rest);
+
+    if !is_inside_directory(Path::new(&rendered)) {
+        bail!(
+            "Invalid directory template `{template}`, `{rendered}` is not a relative path inside the output directory."
+        );
+    }
+    Ok(rendered)
+}
+
+// check_destination_dir checks that the destination of a crate is inside the output directory.
+fn check_destination_dir(destination: &Path) -> CargoResult<()> {
+    if !is_inside_directory(destination) {
+        bail!(
+            "Invalid destination `{}`, it must be a relative path inside the output directory.",
+            destination.display()
+        );
+    }
+    Ok(())
+}
+
+// is_inside_directory returns whether `path` is a relative path which does not leave the
+// directory it is relative to.
+pub(crate) fn is_inside_directory(path: &Path) -> bool {
+    path.components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+}
+
+// validate_name checks that `name` is a valid package name.
+pub(crate) fn validate_name(name: &str) -> CargoResult<()> {
+    let id = PackageIdSpec::parse(name)?;
+    if id.name() != name || id.partial_version().is_some() {
+        bail!("`{name}` is not a valid package name.");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, path::PathBuf};
+
+    use super::*;
+    use tempfile::tempdir;
+
+    fn context() -> GlobalContext {
+        GlobalContext::default().unwrap()
+    }
+
+    #[test]
+    fn test_parse_version_req() {
+        assert_eq!("=12.4.5", parse_version_req("12.4.5").unwrap());
+        assert_eq!("=12.4.5", parse_version_req("=12.4.5").unwrap());
+        assert_eq!("12.2.*", parse_version_req("12.2.*").unwrap());
+    }
+
+    #[test]
+    fn test_is_exact_version_req() {
+        assert!(is_exact_version_req("=1.2.3"));
+        assert!(is_exact_version_req("=1.2.3-alpha.1"));
+        assert!(!is_exact_version_req("=1.2"));
+        assert!(!is_exact_version_req("^1.2.3"));
+        assert!(!is_exact_version_req(">=1.2.3, <1.3"));
+    }
+
+    #[test]
+    fn test_parse_version_req_invalid_req() {
+        assert_eq!(
+            "Invalid version requirement: `=foo`.",
+            parse_version_req("=foo").unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_clone_directory() {
+        let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let from = PathBuf::from(manifest_dir).join("tests/data");
+        let to = tempdir().unwrap();
+        let to_path = to.path();
+
+        clone_directory(&context(), &from, to_path, &CopyOptions::default()).unwrap();
+
+        assert!(to_path.join("Cargo.toml").exists());
+        assert!(!to_path.join(".cargo-ok").exists());
+        assert!(!to_path.join(".cargo-checksum.json").exists());
+    }
+
+    #[test]
+    fn test_clone_directory_link() {
+        let manifest_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
+        let from = PathBuf::from(manifest_dir).join("tests/data");
+        let to = tempdir().unwrap();
+        let to_path = to.path();
+
+        let options = CopyOptions {
+            link: true,
+            ..Default::default()
+        };
+        clone_directory(&context(), &from, to_path, &options).unwrap();
+
+        assert!(to_path.join("Cargo.toml").exists());
+        assert!(!to_path.join(".cargo-ok").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_clone_directory_preserves_metadata() {
+        use std::os::unix::fs::{PermissionsExt, symlink};
+
+        let from = tempdir().unwrap();
+        let from_path = from.path();
+        fs::create_dir(from_path.join("src")).unwrap();
+        fs::write(from_path.join("src/lib.rs"), "").unwrap();
+        fs::write(from_path.join("build.sh"), "").unwrap();
+        fs::set_permissions(
+            from_path.join("build.sh"),
+            fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        symlink("src/lib.rs", from_path.join("link.rs")).unwrap();
+        let mtime = FileTime::from_unix_time(1_000_000_000, 0);
+        filetime::set_file_mtime(from_path.join("src/lib.rs"), mtime).unwrap();
+        filetime::set_file_mtime(from_path.join("src"), mtime).unwrap();
+
+        let to = tempdir().unwrap();
+        let to_path = to.path();
+        clone_directory(&context(), from_path, to_path, &CopyOptions::default()).unwrap();
+
+        let mode = fs::metadata(to_path.join("build.sh"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(0o755, mode & 0o777);
+        assert_eq!(
+            PathBuf::from("src/lib.rs"),
+            fs::read_link(to_path.join("link.rs")).unwrap()
+        );
+        for path in ["src/lib.rs", "src"] {
+            let metadata = fs::metadata(to_path.join(path)).unwrap();
+            assert_eq!(mtime, FileTime::from_last_modification_time(&metadata));
+        }
+
+        let to = tempdir().unwrap();
+        let to_path = to.path();
+        let options = CopyOptions {
+            follow_symlinks: true,
+            ..Default::default()
+        };
+        clone_directory(&context(), from_path, to_path, &options).unwrap();
+
+        let metadata = fs::symlink_metadata(to_path.join("link.rs")).unwrap();
+        assert!(metadata.is_file());
+    }
+
+    // The fixtures of the unreadable tests cannot be read by anyone, including root, unlike
+    // files without read permission.
+    #[cfg(unix)]
+    #[test]
+    fn test_clone_directory_unreadable() {
+        let from = tempdir().unwrap();
+        let from_path = from.path();
+        fs::write(from_path.join("Cargo.toml"), "").unwrap();
+        std::os::unix::fs::symlink("missing", from_path.join("dangling")).unwrap();
+        let options = |skip_unreadable| CopyOptions {
+            follow_symlinks: true,
+            skip_unreadable,
+            ..Default::default()
+        };
+
+        let to = tempdir().unwrap();
+        let err = clone_directory(&context(), from_path, to.path(), &options(false)).unwrap_err();
+
+        assert_eq!(
+            format!("Unable to read `{}`.", from_path.join("dangling").display()),
+            err.to_string()
+        );
+
+        let to = tempdir().unwrap();
+        clone_directory(&context(), from_path, to.path(), &options(true)).unwrap();
+
+        assert!(to.path().join("Cargo.toml").exists());
+        assert!(fs::symlink_metadata(to.path().join("dangling")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_clone_directory_unreadable_file() {
+        use cargo::core::Shell;
+        use std::os::unix::fs::PermissionsExt;
+
+        let from = tempdir().unwrap();
+        let from_path = from.path();
+        fs::write(from_path.join("Cargo.toml"), "").unwrap();
+        fs::write(from_path.join("secret"), "").unwrap();
+        fs::set_permissions(from_path.join("secret"), fs::Permissions::from_mode(0o000)).unwrap();
+        // Root reads files without read permission, so there is nothing to skip.
+        if fs::File::open(from_path.join("secret")).is_ok() {
+            return;
+        }
+        let options = |skip_unreadable| CopyOptions {
+            skip_unreadable,
+            ..Default::default()
+        };
+
+        let to = tempdir().unwrap();
+        let err = clone_directory(&context(), from_path, to.path(), &options(false)).unwrap_err();
+
+        assert_eq!(
+            format!(
+                "Unable to copy `{}` to `{}`.",
+                from_path.join("secret").display(),
+                to.path().join("secret").display()
+            ),
+            err.to_string()
+        );
+
+        let to = tempdir().unwrap();
+        let log = tempdir().unwrap();
+        let log_path = log.path().join("shell.log");
+        let context = context();
+        *context.shell() = Shell::from_write(Box::new(fs::File::create(&log_path).unwrap()));
+        clone_directory(&context, from_path, to.path(), &options(true)).unwrap();
+
+        assert!(to.path().join("Cargo.toml").exists());
+        assert!(!to.path().join("secret").exists());
+        let warnings = fs::read_to_string(&log_path).unwrap();
+        assert!(
+            warnings.contains(&format!(
+                "warning: Skipping `{}`: Permission denied",
+                from_path.join("secret").display()
+            )),
+            "{warnings}"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_clone_directory_unreadable_dir() {
+        let from = tempdir().unwrap();
+        let from_path = from.path();
+        fs::write(from_path.join("Cargo.toml"), "").unwrap();
+        fs::create_dir(from_path.join("private")).unwrap();
+        fs::write(from_path.join("private/data"), "").unwrap();
+        // Following this link loops back to the root of the package.
+        std::os::unix::fs::symlink("..", from_path.join("private/loop")).unwrap();
+        let options = |skip_unreadable| CopyOptions {
+            follow_symlinks: true,
+            skip_unreadable,
+            ..Default::default()
+        };
+
+        let to = tempdir().unwrap();
+        let err = clone_directory(&context(), from_path, to.path(), &options(false)).unwrap_err();
+
+        assert_eq!(
+            format!(
+                "Unable to read `{}`.",
+                from_path.join("private/loop").display()
+            ),
+            err.to_string()
+        );
+
+        let to = tempdir().unwrap();
+        clone_directory(&context(), from_path, to.path(), &options(true)).unwrap();
+
+        assert!(to.path().join("Cargo.toml").exists());
+        assert!(to.path().join("private/data").exists());
+        assert!(!to.path().join("private/loop").exists());
+    }
+
+    #[test]
+    fn test_clone_directory_filter() {
+        let from = tempdir().unwrap();
+        let from_path = from.path();
+        fs::write(from_path.join("Cargo.toml"), "").unwrap();
+        fs::write(from_path.join("README.md"), "readme").unwrap();
+        fs::create_dir_all(from_path.join("src/bin")).unwrap();
+        fs::write(from_path.join("src/lib.rs"), "").unwrap();
+        fs::write(from_path.join("src/bin/main.rs"), "").unwrap();
+        fs::create_dir_all(from_path.join("tests/fixtures")).unwrap();
+        fs::write(from_path.join("tests/fixtures/data"), "data").unwrap();
+
+        let to = tempdir().unwrap();
+        let to_path = to.path();
+        let options = CopyOptions {
+            filter: FileFilter::new(
+                &[
+                    "Cargo.toml".to_string(),
+                    "src".to_string(),
+                    "**/*.md".to_string(),
+                ],
+                &["src/bin".to_string()],
+            )
+            .unwrap(),
+            ..Default::default()
+        };
+        let stats = clone_directory(&context(), from_path, to_path, &options).unwrap();
+
+        assert!(to_path.join("Cargo.toml").exists());
+        assert!(to_path.join("README.md").exists());
+        assert!(to_path.join("src/lib.rs").exists());
+        assert!(!to_path.join("src/bin").exists());
+        assert!(!to_path.join("tests").exists());
+        assert_eq!(
+            CopyStats {
+                skipped_files: 2,
+                skipped_bytes: 4,
+            },
+            stats
+        );
+    }
+
+    #[test]
+    fn test_clone_directory_subpath() {
+        let from = tempdir().unwrap();
+        let from_path = from.path();
+        fs::write(from_path.join("Cargo.toml"), "").unwrap();
+        fs::create_dir_all(from_path.join("src/codec/tests")).unwrap();
+        fs::write(from_path.join("src/lib.rs"), "").unwrap();
+        fs::write(from_path.join("src/codec/mod.rs"), "").unwrap();
+        fs::write(from_path.join("src/codec/tests/data"), "data").unwrap();
+        let options = |subpath: &str| CopyOptions {
+            filter: FileFilter::new(&[], &["src/codec/tests".to_string()]).unwrap(),
+            subpath: Some(PathBuf::from(subpath)),
+            ..Default::default()
+        };
+
+        let to = tempdir().unwrap();
+        let stats =
+            clone_directory(&context(), from_path, to.path(), &options("src/codec/")).unwrap();
+        assert!(to.path().join("mod.rs").exists());
+        assert!(!to.path().join("tests").exists());
+        assert!(!to.path().join("Cargo.toml").exists());
+        assert_eq!(1, stats.skipped_files);
+
+        let to = tempdir().unwrap();
+        clone_directory(&context(), from_path, to.path(), &options("src/lib.rs")).unwrap();
+        assert_eq!(
+            vec![to.path().join("lib.rs")],
+            fs::read_dir(to.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect::<Vec<_>>()
+        );
+
+        let to = tempdir().unwrap();
+        let err =
+            clone_directory(&context(), from_path, to.path(), &options("src/io")).unwrap_err();
+        assert_eq!("`src/io` does not exist in the package.", err.to_string());
+    }
+
+    #[test]
+    fn test_clone_repo() {
+        let to = tempdir().unwrap();
+        let to_path = to.path();
+
+        clone_git_repo("https://github.com/janlikar/cargo-clone", to_path).unwrap();
+
+        assert!(to_path.exists());
+        assert!(to_path.join(".git").exists());
+    }
+
+    #[test]
+    fn test_parse_name_and_version() {
+        assert_eq!(
+            parse_name_and_version("foo").unwrap(),
+            Crate::new(String::from("foo"), None)
+        );
+        assert_eq!(
             parse_name_and_version("foo@1.1.3").unwrap(),
             Crate::new(String::from("foo"), Some(String::from("=1.1.3")))
         );
@@ -361,5 +2326,164 @@ mod tests {
             parse_name_and_version("foo@1.1.*").unwrap(),
             Crate::new(String::from("foo"), Some(String::from("1.1.*")))
         );
+        assert_eq!(
+            parse_name_and_version("foo@1.1.3+build.5").unwrap(),
+            Crate::new(String::from("foo"), Some(String::from("=1.1.3+build.5")))
+        );
+        assert_eq!(
+            parse_name_and_version("my-registry/foo@1.1.3").unwrap(),
+            Crate::new(String::from("foo"), Some(String::from("=1.1.3")))
+                .with_source(ClonerSource::registry("my-registry"))
+        );
+    }
+
+    #[test]
+    fn test_parse_name_and_version_destination() {
+        let crate_ = |version: Option<&str>, destination| {
+            Crate::new(String::from("foo"), version.map(String::from)).with_destination(destination)
+        };
+
+        assert_eq!(
+            parse_name_and_version("foo=vendor/foo").unwrap(),
+            crate_(None, "vendor/foo")
+        );
+        assert_eq!(
+            parse_name_and_version("foo@1.0=vendor/foo").unwrap(),
+            crate_(Some("=1.0"), "vendor/foo")
+        );
+        assert_eq!(
+            parse_name_and_version("foo@>=1.0=vendor/foo").unwrap(),
+            crate_(Some(">=1.0"), "vendor/foo")
+        );
+        assert_eq!(
+            parse_name_and_version("foo@=1.0").unwrap(),
+            Crate::new(String::from("foo"), Some(String::from("=1.0")))
+        );
+        assert_eq!(
+            parse_name_and_version("sparse+https://example.com/index/#foo@1.0=vendor/foo").unwrap(),
+            crate_(Some("=1.0"), "vendor/foo")
+                .with_source(ClonerSource::index("sparse+https://example.com/index/").unwrap())
+        );
+        assert_eq!(
+            "Destination missing in `foo@1.0=`.",
+            parse_name_and_version("foo@1.0=").unwrap_err().to_string()
+        );
+        assert_eq!(
+            "Invalid destination `/etc/foo`, it must be a relative path inside the output directory.",
+            parse_name_and_version("foo=/etc/foo")
+                .unwrap_err()
+                .to_string()
+        );
+        assert!(parse_name_and_version("foo=vendor/../../foo").is_err());
+    }
+
+    #[test]
+    fn test_parse_name_and_version_checksum() {
+        let checksum = "BAF1DE4339761588BC0619E3CBC0120EE582EBB74B53B4EFBF79117BD2DA40FD";
+        let crate_ =
+            Crate::new(String::from("foo"), Some(String::from("=1.0.0"))).with_checksum(checksum);
+
+        assert_eq!(
+            parse_name_and_version(&format!("foo@1.0.0#sha256={checksum}")).unwrap(),
+            crate_
+        );
+        assert_eq!(
+            parse_name_and_version(&format!("foo@1.0.0#sha256={checksum}=vendor/foo")).unwrap(),
+            crate_.with_destination("vendor/foo")
+        );
+        assert_eq!(
+            "Invalid checksum in `foo@1.0.0#sha256=abc`, expected 64 hexadecimal digits after `#sha256=`.",
+            parse_name_and_version("foo@1.0.0#sha256=abc")
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn test_parse_crate_list() {
+        let list = "# Forks\nfoo@1.0 # Pinned\n\n  bar\nhttps://example.com/index#baz\n";
+        assert_eq!(
+            parse_crate_list(list).unwrap(),
+            vec![
+                Crate::new(String::from("foo"), Some(String::from("=1.0"))),
+                Crate::new(String::from("bar"), None),
+                Crate::new(String::from("baz"), None)
+                    .with_source(ClonerSource::index("https://example.com/index").unwrap()),
+            ]
+        );
+
+        let error = parse_crate_list("foo\n\nbar@\n").unwrap_err();
+        assert_eq!(
+            "Invalid crate spec on line 3: Crate version missing in `bar@`.",
+            format!("{error:#}")
+        );
+    }
+
+    #[test]
+    fn test_render_dir_template() {
+        assert_eq!(
+            "foo-1.2.3",
+            render_dir_template("{name}-{version}", "foo", "1.2.3").unwrap()
+        );
+        assert_eq!(
+            "Unknown placeholder `{vers}` in directory template `{name}-{vers}`, expected `{name}` or `{version}`.",
+            render_dir_template("{name}-{vers}", "foo", "1.2.3")
+                .unwrap_err()
+                .to_string()
+        );
+        assert!(render_dir_template("{name", "foo", "1.2.3").is_err());
+        assert_eq!(
+            "Invalid directory template `../{name}`, `../foo` is not a relative path inside the output directory.",
+            render_dir_template("../{name}", "foo", "1.2.3")
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn test_parse_name_and_version_package_id_spec() {
+        assert_eq!(
+            parse_name_and_version(
+                "registry+https://github.com/rust-lang/crates.io-index#foo@1.1.3"
+            )
+            .unwrap(),
+            Crate::new(String::from("foo"), Some(String::from("=1.1.3")))
+                .with_source(ClonerSource::crates_io())
+        );
+        assert_eq!(
+            parse_name_and_version("sparse+https://example.com/index/#foo@1.1").unwrap(),
+            Crate::new(String::from("foo"), Some(String::from("=1.1")))
+                .with_source(ClonerSource::index("sparse+https://example.com/index/").unwrap())
+        );
+        assert_eq!(
+            parse_name_and_version("https://example.com/index#foo").unwrap(),
+            Crate::new(String::from("foo"), None)
+                .with_source(ClonerSource::index("https://example.com/index").unwrap())
+        );
+    }
+
+    #[test]
+    fn test_parse_name_and_version_invalid() {
+        let error = |spec| parse_name_and_version(spec).unwrap_err().to_string();
+
+        assert_eq!("Crate name missing in `@1.1.3`.", error("@1.1.3"));
+        assert_eq!("Crate version missing in `foo@`.", error("foo@"));
+        assert_eq!(
+            "Invalid crate spec `foo@1@2`, `@` can only separate the crate from its version.",
+            error("foo@1@2")
+        );
+        assert_eq!("Invalid crate name in `fo!o`.", error("fo!o"));
+        assert_eq!(
+            "Invalid crate spec `a/b/c`, expected `registry/crate@version`.",
+            error("a/b/c")
+        );
+        assert_eq!(
+            "Crate name missing in `https://example.com/index`, e.g. `https://example.com/index#crate@1.2.3`.",
+            error("https://example.com/index")
+        );
+        assert_eq!(
+            "Cannot clone from the git repository in `git+https://example.com/foo#foo`, use `--git` to clone the repository of a crate.",
+            error("git+https://example.com/foo#foo")
+        );
     }
 }
@@ -0,0 +1,239 @@
+// Copyright 2015 Jan Likar.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, bail};
+use cargo::CargoResult;
+use toml_edit::{ArrayOfTables, DocumentMut, Item, Table, value};
+
+use crate::{ClonedPackage, Crate, SourceId};
+
+const HEADER: &str =
+    "# This file is generated by cargo-clone, it is not intended for manual editing.\n";
+
+/// The versions and checksums selected for the cloned crates, usually saved as a lock file,
+/// to clone exactly the same sources again with [`ClonerBuilder::with_lock`].
+///
+/// A crate is identified by its name, the requested version and its source.
+///
+/// [`ClonerBuilder::with_lock`]: crate::ClonerBuilder::with_lock
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CloneLock {
+    crates: Vec<LockedCrate>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LockedCrate {
+    name: String,
+    /// The requested version, if any.
+    requirement: Option<String>,
+    /// URL of the source the crate was requested from.
+    source: String,
+    version: semver::Version,
+    /// Checksum of the `.crate` archive, as recorded by the source.
+    checksum: String,
+}
+
+impl CloneLock {
+    /// Creates an empty [`CloneLock`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads a lock file.
+    pub fn load(path: impl AsRef<Path>) -> CargoResult<Self> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Unable to read `{}`.", path.display()))?;
+        Self::parse(&contents).with_context(|| format!("Invalid lock file `{}`.", path.display()))
+    }
+
+    /// Parses the contents of a lock file.
+    pub fn parse(contents: &str) -> CargoResult<Self> {
+        let document: DocumentMut = contents.parse()?;
+        let crates = match document.get("crate") {
+            Some(item) => item
+                .as_array_of_tables()
+                .context("Crates must be listed in `[[crate]]` tables.")?
+                .iter()
+                .enumerate()
+                .map(|(i, table)| {
+                    parse_locked_crate(table)
+                        .with_context(|| format!("Invalid crate number {}", i + 1))
+                })
+                .collect::<CargoResult<_>>()?,
+            None => vec![],
+        };
+        Ok(Self { crates })
+    }
+
+    /// Writes the lock file.
+    pub fn save(&self, path: impl AsRef<Path>) -> CargoResult<()> {
+        let path = path.as_ref();
+        fs::write(path, self.to_string())
+            .with_context(|| format!("Unable to write `{}`.", path.display()))
+    }
+
+    /// Records the package cloned for `crate_`, replacing the previous one, if any.
+    ///
+    /// Fails if the source of the package does not record its checksum, since a locked version
+    /// could then not be verified.
+    pub fn insert(&mut self, crate_: &Crate, package: &ClonedPackage) -> CargoResult<()> {
+        let Some(checksum) = &package.checksum else {
+            bail!(
+                "Cannot lock {} {}, {} does not record checksums.",
+                package.name,
+                package.version,
+                package.source_id
+            );
+        };
+        let locked = LockedCrate {
+            name: crate_.name.clone(),
+            requirement: crate_.version.clone(),
+            source: package.source_id.as_url().to_string(),
+            version: package.version.clone(),
+            checksum: checksum.clone(),
+        };
+        match self
+            .crates
+            .iter_mut()
+            .find(|c| c.is_for(crate_, &locked.source))
+        {
+            Some(previous) => *previous = locked,
+            None => self.crates.push(locked),
+        }
+        Ok(())
+    }
+
+    /// The locked version of `crate_` from `srcid`, with its checksum.
+    pub(crate) fn get(&self, crate_: &Crate, srcid: SourceId) -> Option<(&semver::Version, &str)> {
+        let source = srcid.as_url().to_string();
+        self.crates
+            .iter()
+            .find(|c| c.is_for(crate_, &source))
+            .map(|c| (&c.version, c.checksum.as_str()))
+    }
+}
+
+impl LockedCrate {
+    fn is_for(&self, crate_: &Crate, source: &str) -> bool {
+        self.name == crate_.name && self.requirement == crate_.version && self.source == source
+    }
+}
+
+impl fmt::Display for CloneLock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut tables = ArrayOfTables::new();
+        for locked in &self.crates {
+            let mut table = Table::new();
+            table["name"] = value(&locked.name);
+            if let Some(requirement) = &locked.requirement {
+                table["requirement"] = value(requirement);
+            }
+            table["source"] = value(&locked.source);
+            table["version"] = value(locked.version.to_string());
+            table["checksum"] = value(&locked.checksum);
+            tables.push(table);
+        }
+
+        let mut document = DocumentMut::new();
+        document["crate"] = Item::ArrayOfTables(tables);
+        write!(f, "{HEADER}{document}")
+    }
+}
+
+fn parse_locked_crate(table: &Table) -> CargoResult<LockedCrate> {
+    let get = |key: &str| -> CargoResult<Option<String>> {
+        table
+            .get(key)
+            .map(|item| {
+                item.as_str()
+                    .map(str::to_owned)
+                    .with_context(|| format!("`{key}` must be a string."))
+            })
+            .transpose()
+    };
+    if let Some((key, _)) = table.iter().find(|(key, _)| {
+        !matches!(
+            *key,
+            "name" | "requirement" | "source" | "version" | "checksum"
+        )
+    }) {
+        bail!("Unknown key `{key}`.");
+    }
+
+    let version = get("version")?.context("`version` is missing.")?;
+    Ok(LockedCrate {
+        name: get("name")?.context("`name` is missing.")?,
+        requirement: get("requirement")?,
+        source: get("source")?.context("`source` is missing.")?,
+        version: version
+            .parse()
+            .with_context(|| format!("Invalid version `{version}`."))?,
+        checksum: get("checksum")?.context("`checksum` is missing.")?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    use cargo::util::GlobalContext;
+
+    #[test]
+    fn test_round_trip() {
+        let context = GlobalContext::default().unwrap();
+        let srcid = SourceId::crates_io(&context).unwrap();
+        let crate_ = Crate::new(String::from("foo"), Some(String::from("~1.2")));
+        let package = ClonedPackage {
+            name: "foo".to_string(),
+            version: semver::Version::new(1, 2, 3),
+            source_id: srcid,
+            replaced_source_id: srcid,
+            checksum: Some("abc".to_string()),
+            path: PathBuf::from("foo"),
+            skipped_files: 0,
+            skipped_bytes: 0,
+        };
+
+        let mut lock = CloneLock::new();
+        lock.insert(&crate_, &package).unwrap();
+        lock.insert(&crate_, &package).unwrap();
+        let contents = lock.to_string();
+
+        assert_eq!(
+            format!(
+                "{HEADER}[[crate]]\nname = \"foo\"\nrequirement = \"~1.2\"\nsource = \"{}\"\nversion = \"1.2.3\"\nchecksum = \"abc\"\n",
+                srcid.as_url()
+            ),
+            contents
+        );
+        let parsed = CloneLock::parse(&contents).unwrap();
+        assert_eq!(lock, parsed);
+        assert_eq!(
+            Some((&semver::Version::new(1, 2, 3), "abc")),
+            parsed.get(&crate_, srcid)
+        );
+        assert_eq!(
+            None,
+            parsed.get(&Crate::new(String::from("foo"), None), srcid)
+        );
+
+        let unchecked = ClonedPackage {
+            checksum: None,
+            ..package
+        };
+        assert!(lock.insert(&crate_, &unchecked).is_err());
+        assert!(CloneLock::parse(&contents.replace("checksum = \"abc\"\n", "")).is_err());
+    }
+}
@@ -6,23 +6,41 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+use anyhow::bail;
 use cargo::util::GlobalContext;
 use cargo::{CargoResult, core::SourceId, util::IntoUrl};
 use url::Url;
 
 /// Where to clone the crate from.
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct ClonerSource {
     pub(crate) cargo_source: CargoSource,
 }
 
-#[derive(Debug, Default)]
+/// How to treat source replacement configured in the `[source]` table of cargo's configuration.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SourceReplacement {
+    /// Follow the configured replacement, if any.
+    #[default]
+    Allow,
+    /// Ignore the configured replacement and clone from the original source.
+    Ignore,
+    /// Fail if the source is not replaced.
+    Require,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub(crate) enum CargoSource {
     #[default]
     CratesIo,
     Index(Url),
     LocalRegistry(String),
+    Directory(String),
     Registry(String),
+    CrateFile {
+        location: String,
+        sha256: Option<String>,
+    },
 }
 
 impl ClonerSource {
@@ -40,6 +58,29 @@ impl ClonerSource {
         }
     }
 
+    /// Creates a [`ClonerSource`] from a directory source, such as the output of `cargo vendor`.
+    ///
+    /// Packages are verified against their `.cargo-checksum.json` before being cloned.
+    pub fn directory(path: impl Into<String>) -> Self {
+        Self {
+            cargo_source: CargoSource::Directory(path.into()),
+        }
+    }
+
+    /// Creates a [`ClonerSource`] from a standalone `.crate` archive.
+    ///
+    /// `location` is either a local path or a `file://`, `http://` or `https://` URL.
+    /// If `sha256` is specified, the archive must match it.
+    /// The archive is fetched and unpacked when the first crate is cloned from it.
+    pub fn crate_file(location: impl Into<String>, sha256: Option<String>) -> Self {
+        Self {
+            cargo_source: CargoSource::CrateFile {
+                location: location.into(),
+                sha256,
+            },
+        }
+    }
+
     /// Creates a [`ClonerSource`] from a remote registry URL.
     pub fn index(index: impl AsRef<str>) -> CargoResult<Self> {
         let index: &str = index.as_ref();
@@ -47,7 +88,11 @@ impl ClonerSource {
         Ok(Self { cargo_source })
     }
 
-    /// Creates a [`ClonerSource`] from a remote registry URL.
+    /// Creates a [`ClonerSource`] from an already parsed registry URL,
+    /// such as the one given in a `registry+<url>#name` crate spec.
+    ///
+    /// Unlike [`ClonerSource::index`], the URL is not parsed again, so this cannot fail.
+    /// A `sparse+` prefix of the URL is kept and selects the sparse protocol.
     pub fn index_from_url(url: Url) -> Self {
         let cargo_source = CargoSource::Index(url);
         Self { cargo_source }
@@ -69,7 +114,12 @@ impl CargoSource {
             CargoSource::LocalRegistry(path) => {
                 SourceId::for_local_registry(&context.cwd().join(path))
             }
+            CargoSource::Directory(path) => SourceId::for_directory(&context.cwd().join(path)),
             CargoSource::Registry(key) => SourceId::alt_registry(context, key),
+            // Cloner::source_id unpacks the archive and serves it as a path source.
+            CargoSource::CrateFile { .. } => {
+                bail!("A crate file must be unpacked before it can be used as a source.")
+            }
         }
     }
 }
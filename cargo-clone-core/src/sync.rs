@@ -0,0 +1,495 @@
+// Copyright 2015 Jan Likar.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, bail};
+use cargo::CargoResult;
+use cargo::core::Summary;
+use cargo::sources::CRATES_IO_INDEX;
+use cargo::sources::registry::CRATES_IO_HTTP_INDEX;
+use cargo_util::Sha256;
+use toml_edit::{DocumentMut, Item, Table, value};
+use url::Url;
+
+use crate::diff::{list_files, read_file};
+use crate::{
+    ClonedPackage, ClonerSource, Crate, FileFilter, SourceId, is_inside_directory,
+    parse_version_req, validate_name,
+};
+
+/// Name of the file recording the pristine sources of a clone made by
+/// [`Cloner::sync`](crate::Cloner::sync).
+pub(crate) const RECORD_FILE: &str = ".cargo-clone.toml";
+
+const RECORD_HEADER: &str =
+    "# This file is generated by cargo-clone, it records the pristine sources of this clone.\n";
+
+/// A list of crates to keep cloned, usually read from a `clones.toml` file.
+///
+/// ```toml
+/// [[crate]]
+/// name = "serde"
+/// version = "~1.0.200"
+/// registry = "my-registry"
+/// path = "vendor/serde"
+/// mode = "git-init"
+/// ```
+///
+/// Only `name` is required. `version` is matched like on the command line, exactly by default.
+/// `registry` or `index` select the source of the crate instead of the source of the
+/// [`Cloner`](crate::Cloner). `path` is a subdirectory of the directory of the file and
+/// defaults to the crate name. `mode` is either `copy`, the default, or `git-init` to commit
+/// the pristine sources into a git repository.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CloneManifest {
+    pub(crate) entries: Vec<CloneEntry>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct CloneEntry {
+    /// The crate, whose destination is always set.
+    pub(crate) crate_: Crate,
+    /// If true, initialize a git repository in the destination and commit the pristine sources.
+    pub(crate) git_init: bool,
+}
+
+impl CloneManifest {
+    /// Reads a manifest from `path`.
+    pub fn load(path: impl AsRef<Path>) -> CargoResult<Self> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("Unable to read `{}`.", path.display()))?;
+        let base = path.parent().unwrap_or(Path::new(""));
+        Self::parse(&contents, base)
+            .with_context(|| format!("Invalid clone manifest `{}`.", path.display()))
+    }
+
+    /// Parses a manifest, whose paths are relative to `base`.
+    pub fn parse(contents: &str, base: &Path) -> CargoResult<Self> {
+        let document: DocumentMut = contents.parse()?;
+        if let Some((key, _)) = document.iter().find(|(key, _)| *key != "crate") {
+            bail!("Unknown key `{key}`, crates are listed in `[[crate]]` tables.");
+        }
+        let Some(item) = document.get("crate") else {
+            return Ok(Self { entries: vec![] });
+        };
+
+        let tables = item
+            .as_array_of_tables()
+            .context("Crates must be listed in `[[crate]]` tables.")?;
+        let entries = tables
+            .iter()
+            .enumerate()
+            .map(|(i, table)| {
+                parse_entry(table, base).with_context(|| format!("Invalid crate number {}", i + 1))
+            })
+            .collect::<CargoResult<_>>()?;
+        Ok(Self { entries })
+    }
+
+    /// The crates of the manifest.
+    pub fn crates(&self) -> impl Iterator<Item = &Crate> {
+        self.entries.iter().map(|entry| &entry.crate_)
+    }
+}
+
+fn parse_entry(table: &Table, base: &Path) -> CargoResult<CloneEntry> {
+    let mut name = None;
+    let mut version = None;
+    let mut source = None;
+    let mut path = None;
+    let mut git_init = false;
+
+    for (key, item) in table.iter() {
+        let value = item
+            .as_str()
+            .with_context(|| format!("`{key}` must be a string."))?;
+        match key {
+            "name" => name = Some(value),
+            "version" => version = Some(parse_version_req(value)?),
+            "registry" | "index" if source.is_some() => {
+                bail!("Only one of `registry` and `index` can be specified.")
+            }
+            "registry" => source = Some(ClonerSource::registry(value)),
+            "index" => source = Some(ClonerSource::index(value)?),
+            "path" => path = Some(value),
+            "mode" => {
+                git_init = match value {
+                    "copy" => false,
+                    "git-init" => true,
+                    _ => bail!("Unknown mode `{value}`, expected `copy` or `git-init`."),
+                }
+            }
+            _ => bail!("Unknown key `{key}`."),
+        }
+    }
+
+    let name = name.context("`name` is missing.")?;
+    validate_name(name)?;
+    if let Some(path) = path {
+        // The clone is replaced on updates, so it must not be the directory itself or outside it.
+        let inside = Path::new(path)
+            .components()
+            .any(|c| matches!(c, Component::Normal(_)));
+        if !inside || !is_inside_directory(Path::new(path)) {
+            bail!("Invalid path `{path}`, it must be a relative path to a subdirectory.");
+        }
+    }
+    let mut crate_ =
+        Crate::new(name.to_owned(), version).with_destination(base.join(path.unwrap_or(name)));
+    if let Some(source) = source {
+        crate_ = crate_.with_source(source);
+    }
+    Ok(CloneEntry { crate_, git_init })
+}
+
+/// A crate of a [`CloneManifest`] after [`Cloner::sync`](crate::Cloner::sync).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncedCrate {
+    /// Name of the crate.
+    pub name: String,
+    /// Version selected among the versions matching the requested one.
+    pub version: semver::Version,
+    /// Directory the crate is cloned into.
+    pub path: PathBuf,
+    /// What was found in the directory, or done to it.
+    pub status: SyncStatus,
+}
+
+/// State of the clone of a crate of a [`CloneManifest`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncStatus {
+    /// The clone was missing and has been created.
+    Cloned,
+    /// The clone has the selected version.
+    UpToDate,
+    /// The clone has another version or source, as recorded when it was cloned.
+    Drifted(semver::Version),
+    /// The clone had another version, and has been updated to the selected one.
+    Updated(semver::Version),
+}
+
+/// Where the sources of a clone come from, and the checksums of its files when it was cloned,
+/// saved as [`RECORD_FILE`] in the clone.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct CloneRecord {
+    pub(crate) name: String,
+    pub(crate) version: semver::Version,
+    /// URL of the source the package was requested from.
+    source: String,
+    /// Checksum of the `.crate` archive, as recorded by the source.
+    checksum: Option<String>,
+    /// SHA-256 checksums of the files, keyed by their path relative to the clone.
+    files: BTreeMap<String, String>,
+}
+
+impl CloneRecord {
+    /// Records the files of a package which has just been cloned.
+    pub(crate) fn new(package: &ClonedPackage) -> CargoResult<Self> {
+        Ok(Self {
+            name: package.name.clone(),
+            version: package.version.clone(),
+            source: package.source_id.as_url().to_string(),
+            checksum: package.checksum.clone(),
+            files: hash_files(&package.path)?,
+        })
+    }
+
+    /// Reads the record of the clone in `dir`, if there is one.
+    pub(crate) fn load(dir: &Path) -> CargoResult<Option<Self>> {
+        let path = dir.join(RECORD_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Unable to read `{}`.", path.display()))?;
+        Self::parse(&contents)
+            .map(Some)
+            .with_context(|| format!("Invalid clone record `{}`.", path.display()))
+    }
+
+    fn parse(contents: &str) -> CargoResult<Self> {
+        let document: DocumentMut = contents.parse()?;
+        let string = |key: &str| {
+            document
+                .get(key)
+                .and_then(Item::as_str)
+                .with_context(|| format!("`{key}` is missing."))
+        };
+        let files = document
+            .get("files")
+            .and_then(Item::as_table)
+            .context("`files` is missing.")?
+            .iter()
+            .map(|(path, item)| {
+                let checksum = item
+                    .as_str()
+                    .with_context(|| format!("The checksum of `{path}` must be a string."))?;
+                Ok((path.to_owned(), checksum.to_owned()))
+            })
+            .collect::<CargoResult<_>>()?;
+
+        Ok(Self {
+            name: string("name")?.to_owned(),
+            version: string("version")?.parse()?,
+            source: string("source")?.to_owned(),
+            checksum: document
+                .get("checksum")
+                .and_then(Item::as_str)
+                .map(str::to_owned),
+            files,
+        })
+    }
+
+    /// Writes the record into the clone in `dir`.
+    pub(crate) fn save(&self, dir: &Path) -> CargoResult<()> {
+        let mut document = DocumentMut::new();
+        document["name"] = value(&self.name);
+        document["version"] = value(self.version.to_string());
+        document["source"] = value(&self.source);
+        if let Some(checksum) = &self.checksum {
+            document["checksum"] = value(checksum);
+        }
+        let mut files = Table::new();
+        for (path, checksum) in &self.files {
+            files[path.as_str()] = value(checksum);
+        }
+        document["files"] = Item::Table(files);
+
+        let path = dir.join(RECORD_FILE);
+        fs::write(&path, format!("{RECORD_HEADER}{document}"))
+            .with_context(|| format!("Unable to write `{}`.", path.display()))
+    }
+
+    /// Returns the crate of the clone, with its exact version and the source it was requested
+    /// from. The source is left unset if it cannot be loaded again, like the temporary directory
+    /// a `.crate` archive was unpacked into.
+    pub(crate) fn crate_(&self) -> Crate {
+        let crate_ = Crate::new(self.name.clone(), Some(format!("={}", self.version)));
+        match self.cloner_source() {
+            Some(source) => crate_.with_source(source),
+            None => crate_,
+        }
+    }
+
+    // cloner_source parses the recorded URL of the source, as written by `SourceId::as_url`.
+    fn cloner_source(&self) -> Option<ClonerSource> {
+        if self.source == CRATES_IO_HTTP_INDEX {
+            return Some(ClonerSource::crates_io());
+        }
+        let (kind, url) = self.source.split_once('+')?;
+        let path = || {
+            Url::parse(url)
+                .ok()?
+                .to_file_path()
+                .ok()?
+                .to_str()
+                .map(str::to_owned)
+        };
+        match kind {
+            "registry" if url == CRATES_IO_INDEX => Some(ClonerSource::crates_io()),
+            "registry" => Some(ClonerSource::index_from_url(Url::parse(url).ok()?)),
+            // The URL of sparse registries keeps its `sparse+` prefix.
+            "sparse" => Some(ClonerSource::index_from_url(Url::parse(&self.source).ok()?)),
+            "local-registry" => Some(ClonerSource::local_registry(path()?)),
+            "directory" => Some(ClonerSource::directory(path()?)),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the clone has the package of `summary`, from the source `srcid`.
+    pub(crate) fn is_for(&self, summary: &Summary, srcid: SourceId) -> bool {
+        self.name == summary.name().as_str()
+            && self.version == *summary.version()
+            && self.source == srcid.as_url().to_string()
+            && self.checksum.as_deref() == summary.checksum()
+    }
+
+    /// Returns the files of the clone in `dir` which were added, removed or modified since it
+    /// was cloned.
+    pub(crate) fn changed_files(&self, dir: &Path) -> CargoResult<Vec<String>> {
+        let files = hash_files(dir)?;
+        let mut changed = files
+            .iter()
+            .filter(|&(path, checksum)| self.files.get(path) != Some(checksum))
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+        changed.extend(
+            self.files
+                .keys()
+                .filter(|path| !files.contains_key(*path))
+                .cloned(),
+        );
+        changed.sort();
+        Ok(changed)
+    }
+}
+
+// hash_files returns the SHA-256 checksums of the files of the clone in `dir`, skipping the
+// files which are not compared by `--diff-dir`, like `.git` and the record itself.
+fn hash_files(dir: &Path) -> CargoResult<BTreeMap<String, String>> {
+    list_files(dir, &FileFilter::default())?
+        .into_iter()
+        .map(|(relative, path)| {
+            let checksum = Sha256::new().update(&read_file(&path)?).finish_hex();
+            Ok((relative, checksum))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let manifest = CloneManifest::parse(
+            r#"
+[[crate]]
+name = "foo"
+
+[[crate]]
+name = "bar"
+version = "~1.2"
+registry = "my-registry"
+path = "forks/bar"
+mode = "git-init"
+"#,
+            Path::new("vendor"),
+        )
+        .unwrap();
+
+        let expected = vec![
+            CloneEntry {
+                crate_: Crate::new(String::from("foo"), None).with_destination("vendor/foo"),
+                git_init: false,
+            },
+            CloneEntry {
+                crate_: Crate::new(String::from("bar"), Some(String::from("~1.2")))
+                    .with_source(ClonerSource::registry("my-registry"))
+                    .with_destination("vendor/forks/bar"),
+                git_init: true,
+            },
+        ];
+        assert_eq!(expected, manifest.entries);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        let error = |contents: &str| {
+            let error = CloneManifest::parse(contents, Path::new("")).unwrap_err();
+            format!("{error:#}")
+        };
+
+        assert_eq!(
+            "Invalid crate number 1: `name` is missing.",
+            error("[[crate]]\nversion = \"1.0\"\n")
+        );
+        assert_eq!(
+            "Invalid crate number 2: Unknown mode `fork`, expected `copy` or `git-init`.",
+            error("[[crate]]\nname = \"foo\"\n[[crate]]\nname = \"bar\"\nmode = \"fork\"\n")
+        );
+        assert_eq!(
+            "Unknown key `crates`, crates are listed in `[[crate]]` tables.",
+            error("[[crates]]\nname = \"foo\"\n")
+        );
+        for path in [".", "", "..", "../foo", "/tmp/foo", "foo/../.."] {
+            assert_eq!(
+                format!(
+                    "Invalid crate number 1: Invalid path `{path}`, it must be a relative path to a subdirectory."
+                ),
+                error(&format!("[[crate]]\nname = \"foo\"\npath = \"{path}\"\n"))
+            );
+        }
+    }
+
+    #[test]
+    fn test_record_crate() {
+        let record = |srcid: SourceId| CloneRecord {
+            name: String::from("foo"),
+            version: "0.1.0".parse().unwrap(),
+            source: srcid.as_url().to_string(),
+            checksum: None,
+            files: BTreeMap::new(),
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+        let url = |url: &str| Url::parse(url).unwrap();
+        let foo = || Crate::new(String::from("foo"), Some(String::from("=0.1.0")));
+
+        for (srcid, source) in [
+            (
+                SourceId::for_registry(&url(CRATES_IO_INDEX)).unwrap(),
+                ClonerSource::crates_io(),
+            ),
+            (
+                SourceId::for_registry(&url(CRATES_IO_HTTP_INDEX)).unwrap(),
+                ClonerSource::crates_io(),
+            ),
+            (
+                SourceId::for_registry(&url("https://example.com/index")).unwrap(),
+                ClonerSource::index("https://example.com/index").unwrap(),
+            ),
+            (
+                SourceId::for_registry(&url("sparse+https://example.com/index/")).unwrap(),
+                ClonerSource::index("sparse+https://example.com/index/").unwrap(),
+            ),
+            (
+                SourceId::for_local_registry(dir.path()).unwrap(),
+                ClonerSource::local_registry(path),
+            ),
+            (
+                SourceId::for_directory(dir.path()).unwrap(),
+                ClonerSource::directory(path),
+            ),
+        ] {
+            assert_eq!(record(srcid).crate_(), foo().with_source(source));
+        }
+        // The directory a `.crate` archive was unpacked into is gone.
+        let srcid = SourceId::for_path(dir.path()).unwrap();
+        assert_eq!(record(srcid).crate_(), foo());
+    }
+
+    #[test]
+    fn test_record() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("Cargo.toml"), "[package]\n").unwrap();
+        fs::write(dir.path().join("src/lib.rs"), "").unwrap();
+
+        let package = ClonedPackage {
+            name: String::from("foo"),
+            version: "0.1.0".parse().unwrap(),
+            source_id: SourceId::for_path(dir.path()).unwrap(),
+            replaced_source_id: SourceId::for_path(dir.path()).unwrap(),
+            checksum: Some(String::from("abc")),
+            path: dir.path().to_owned(),
+            skipped_files: 0,
+            skipped_bytes: 0,
+        };
+        CloneRecord::new(&package)
+            .unwrap()
+            .save(dir.path())
+            .unwrap();
+
+        let record = CloneRecord::load(dir.path()).unwrap().unwrap();
+        assert_eq!(record, CloneRecord::new(&package).unwrap());
+        assert!(record.changed_files(dir.path()).unwrap().is_empty());
+
+        fs::write(dir.path().join("src/lib.rs"), "fn foo() {}\n").unwrap();
+        fs::remove_file(dir.path().join("Cargo.toml")).unwrap();
+        fs::write(dir.path().join("README.md"), "").unwrap();
+        assert_eq!(
+            record.changed_files(dir.path()).unwrap(),
+            ["Cargo.toml", "README.md", "src/lib.rs"]
+        );
+    }
+}
@@ -1,5 +1,150 @@
-use cargo_clone_core::ClonerBuilder;
+use std::{
+    env, fs,
+    io::Read,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use cargo::core::{Shell, SourceId};
+use cargo::util::GlobalContext;
+use cargo::util::hex::short_hash;
+use cargo_clone_core::{
+    ArchiveFormat, BisectCommand, Bisection, CloneLock, CloneManifest, ClonerBuilder, ClonerSource,
+    SourceReplacement, SyncStatus, VersionSelection,
+};
+use cargo_util::Sha256;
+use flate2::{Compression, write::GzEncoder};
 use tempfile::tempdir;
+use url::Url;
+
+fn manifest(name: &str, version: &str) -> String {
+    format!("[package]\nname = \"{name}\"\nversion = \"{version}\"\nedition = \"2021\"\n")
+}
+
+// directory_source creates a directory source in `dir` with a library package per version.
+fn directory_source(dir: &Path, name: &str, versions: &[&str]) {
+    for version in versions {
+        let package = dir.join(format!("{name}-{version}"));
+        fs::create_dir_all(package.join("src")).unwrap();
+        fs::write(package.join("Cargo.toml"), manifest(name, version)).unwrap();
+        fs::write(package.join("src/lib.rs"), "").unwrap();
+        fs::write(package.join(".cargo-checksum.json"), r#"{"files":{}}"#).unwrap();
+    }
+}
+
+// git runs git in `dir` and returns its output.
+fn git(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new("git")
+        .current_dir(dir)
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "git {args:?} failed");
+    String::from_utf8(output.stdout).unwrap()
+}
+
+// crate_archive returns a `.crate` archive of a library package.
+fn crate_archive(name: &str, version: &str) -> Vec<u8> {
+    let manifest = manifest(name, version);
+    let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    for (path, data) in [("Cargo.toml", manifest.as_bytes()), ("src/lib.rs", b"")] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        builder
+            .append_data(&mut header, format!("{name}-{version}/{path}"), data)
+            .unwrap();
+    }
+    builder.into_inner().unwrap().finish().unwrap()
+}
+
+// index_file returns the path of the index file of `name` in a registry index.
+fn index_file(name: &str) -> PathBuf {
+    match name.len() {
+        1 | 2 => PathBuf::from(format!("{}/{name}", name.len())),
+        3 => PathBuf::from(format!("3/{}/{name}", &name[..1])),
+        _ => PathBuf::from(format!("{}/{}/{name}", &name[..2], &name[2..4])),
+    }
+}
+
+// write_index writes the `.crate` archives of the versions of `name` into `archives`, and their
+// index file into `index`. A version is yanked if its flag is set.
+fn write_index(index: &Path, archives: &Path, name: &str, versions: &[(&str, bool)]) {
+    let index_path = index.join(index_file(name));
+    fs::create_dir_all(index_path.parent().unwrap()).unwrap();
+    fs::create_dir_all(archives).unwrap();
+
+    let mut lines = String::new();
+    for (version, yanked) in versions {
+        let archive = crate_archive(name, version);
+        let cksum = Sha256::new().update(&archive).finish_hex();
+        fs::write(archives.join(format!("{name}-{version}.crate")), archive).unwrap();
+        lines.push_str(&format!(
+            r#"{{"name":"{name}","vers":"{version}","deps":[],"cksum":"{cksum}","features":{{}},"yanked":{yanked}}}"#
+        ));
+        lines.push('\n');
+    }
+    fs::write(index_path, lines).unwrap();
+}
+
+// local_registry creates a local registry in `dir` with a library package per version, which
+// is yanked if its flag is set.
+fn local_registry(dir: &Path, name: &str, versions: &[(&str, bool)]) {
+    write_index(&dir.join("index"), dir, name, versions);
+}
+
+// git_registry creates a remote registry in `dir`, whose git index and `.crate` archives are
+// fetched from `file://` URLs, with a library package per version. Returns the index URL.
+fn git_registry(dir: &Path, packages: &[(&str, &[&str])]) -> String {
+    let index_path = dir.join("index");
+    let archives_path = dir.join("dl");
+    for (name, versions) in packages {
+        let versions = versions.iter().map(|v| (*v, false)).collect::<Vec<_>>();
+        write_index(&index_path, &archives_path, name, &versions);
+    }
+    let dl = Url::from_directory_path(&archives_path).unwrap();
+    fs::write(
+        index_path.join("config.json"),
+        format!(r#"{{"dl":"{dl}{{crate}}-{{version}}.crate"}}"#),
+    )
+    .unwrap();
+
+    git(&index_path, &["init", "--quiet"]);
+    git(&index_path, &["add", "--all"]);
+    git(
+        &index_path,
+        &[
+            "-c",
+            "user.name=Test",
+            "-c",
+            "user.email=test@localhost",
+            "commit",
+            "--quiet",
+            "--message",
+            "Index",
+        ],
+    );
+    Url::from_directory_path(&index_path).unwrap().to_string()
+}
+
+// cargo_context returns a cargo context whose home is `home`, so that it starts with empty caches.
+fn cargo_context(home: &Path, offline: bool) -> GlobalContext {
+    let cwd = env::current_dir().unwrap();
+    let mut context = GlobalContext::new(Shell::new(), cwd, home.to_owned());
+    context
+        .configure(0, true, None, false, false, offline, &None, &[], &[])
+        .unwrap();
+    context
+}
+
+// extracted_dir returns the directory in which cargo extracts `name-version` from the remote
+// registry at `index` in `home`.
+fn extracted_dir(home: &Path, index: &str, name: &str, version: &str) -> PathBuf {
+    let srcid = SourceId::for_registry(&Url::parse(index).unwrap()).unwrap();
+    home.join("registry/src")
+        .join(format!("-{}", short_hash(&srcid)))
+        .join(format!("{name}-{version}"))
+}
 
 #[test]
 fn test_from_registry_in_dir() {
@@ -94,3 +239,880 @@ fn test_multi_crates() {
     assert!(output_path.join("tokio").exists());
     assert!(output_path.join("tokio").join("Cargo.toml").exists());
 }
+
+#[test]
+fn test_from_directory_source() {
+    let temp_dir = tempdir().unwrap();
+    let output_path = temp_dir.path().join("foo");
+    let vendor_path = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/vendor");
+
+    let crate_ = cargo_clone_core::Crate::new(String::from("foo"), None);
+
+    let cloner = ClonerBuilder::new()
+        .with_source(ClonerSource::directory(vendor_path.to_str().unwrap()))
+        .with_directory(&output_path)
+        .build()
+        .unwrap();
+
+    let cloned = cloner.clone_in_dir(&crate_).unwrap();
+
+    assert_eq!(cloned.name, "foo");
+    assert_eq!(cloned.version.to_string(), "0.1.0");
+    assert_eq!(cloned.source_id, cloned.replaced_source_id);
+    assert_eq!(cloned.path, output_path);
+    assert!(output_path.join("Cargo.toml").exists());
+    assert!(output_path.join("src").join("lib.rs").exists());
+    assert!(!output_path.join(".cargo-checksum.json").exists());
+}
+
+#[test]
+fn test_from_tampered_directory_source() {
+    let temp_dir = tempdir().unwrap();
+    let output_path = temp_dir.path().join("foo");
+    // `src/lib.rs` was changed after its checksum was recorded in `.cargo-checksum.json`.
+    let vendor_path =
+        PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/vendor-tampered");
+
+    let crate_ = cargo_clone_core::Crate::new(String::from("foo"), None);
+
+    let cloner = ClonerBuilder::new()
+        .with_source(ClonerSource::directory(vendor_path.to_str().unwrap()))
+        .with_directory(&output_path)
+        .build()
+        .unwrap();
+
+    let err = cloner.clone_in_dir(&crate_).unwrap_err();
+    assert!(format!("{err:#}").contains("checksum"), "{err:#}");
+    assert!(!output_path.join("src/lib.rs").exists());
+}
+
+#[test]
+fn test_crate_file_source_of_crate() {
+    let temp_dir = tempdir().unwrap();
+    let archive = temp_dir.path().join("foo-0.3.0.crate");
+    fs::write(&archive, crate_archive("foo", "0.3.0")).unwrap();
+    let missing = temp_dir.path().join("missing.crate");
+
+    // The archive of the cloner is only fetched when a crate is cloned from it.
+    let cloner = ClonerBuilder::new()
+        .with_source(ClonerSource::crate_file(missing.to_str().unwrap(), None))
+        .with_directory(temp_dir.path())
+        .build()
+        .unwrap();
+
+    let crate_ = cargo_clone_core::Crate::new(String::from("foo"), None)
+        .with_source(ClonerSource::crate_file(archive.to_str().unwrap(), None));
+    let cloned = cloner.clone(&[crate_]).unwrap();
+
+    assert_eq!(cloned[0].version.to_string(), "0.3.0");
+    assert_eq!(cloned[0].path, temp_dir.path().join("foo"));
+    assert!(temp_dir.path().join("foo/src/lib.rs").exists());
+}
+
+#[test]
+fn test_all_matching_from_directory_source() {
+    let temp_dir = tempdir().unwrap();
+    let vendor_path = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/vendor");
+
+    let crate_ = cargo_clone_core::Crate::new(String::from("foo"), None);
+
+    let cloner = ClonerBuilder::new()
+        .with_source(ClonerSource::directory(vendor_path.to_str().unwrap()))
+        .with_all_matching(true)
+        .with_directory(temp_dir.path())
+        .build()
+        .unwrap();
+
+    let cloned = cloner.clone(&[crate_]).unwrap();
+
+    assert_eq!(cloned.len(), 1);
+    assert_eq!(cloned[0].path, temp_dir.path().join("foo-0.1.0"));
+    assert!(temp_dir.path().join("foo-0.1.0/Cargo.toml").exists());
+}
+
+#[test]
+fn test_history_from_directory_source() {
+    let temp_dir = tempdir().unwrap();
+    let vendor_path = temp_dir.path().join("vendor");
+    directory_source(&vendor_path, "foo", &["0.1.0", "0.2.0", "0.3.0"]);
+    fs::write(vendor_path.join("foo-0.1.0/src/old.rs"), "").unwrap();
+    let output_path = temp_dir.path().join("foo");
+
+    let cloner = ClonerBuilder::new()
+        .with_source(ClonerSource::directory(vendor_path.to_str().unwrap()))
+        .with_history(true)
+        .with_directory(&output_path)
+        .build()
+        .unwrap();
+
+    let crate_ = cargo_clone_core::Crate::new(String::from("foo"), None);
+    let cloned = cloner.clone_in_dir(&crate_).unwrap();
+
+    assert_eq!(cloned.version.to_string(), "0.3.0");
+    assert_eq!(
+        "3",
+        git(&output_path, &["rev-list", "--count", "HEAD"]).trim()
+    );
+    assert_eq!("v0.1.0\nv0.2.0\nv0.3.0\n", git(&output_path, &["tag"]));
+    assert_eq!(
+        "foo 0.1.0\n",
+        git(&output_path, &["log", "-1", "--format=%s", "v0.1.0"])
+    );
+    assert_eq!(
+        "Cargo.toml\nsrc/lib.rs\nsrc/old.rs\n",
+        git(&output_path, &["ls-tree", "-r", "--name-only", "v0.1.0"])
+    );
+    // Files removed by a version are removed from its commit.
+    assert_eq!(
+        "Cargo.toml\nsrc/lib.rs\n",
+        git(&output_path, &["ls-tree", "-r", "--name-only", "HEAD"])
+    );
+    assert_eq!("", git(&output_path, &["status", "--porcelain"]));
+}
+
+#[test]
+fn test_git_init_from_directory_source() {
+    let temp_dir = tempdir().unwrap();
+    let output_path = temp_dir.path().join("foo");
+    let vendor_path = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/vendor");
+    let builder = || {
+        ClonerBuilder::new()
+            .with_source(ClonerSource::directory(vendor_path.to_str().unwrap()))
+            .with_git_init(true)
+            .with_directory(&output_path)
+    };
+
+    let crate_ = cargo_clone_core::Crate::new(String::from("foo"), None);
+    builder().build().unwrap().clone_in_dir(&crate_).unwrap();
+
+    assert_eq!(
+        "1",
+        git(&output_path, &["rev-list", "--count", "HEAD"]).trim()
+    );
+    assert_eq!(
+        "Cargo.toml\nsrc/lib.rs\n",
+        git(&output_path, &["ls-tree", "-r", "--name-only", "HEAD"])
+    );
+    assert_eq!("", git(&output_path, &["status", "--porcelain"]));
+
+    assert!(builder().with_link(true).build().is_err());
+}
+
+#[test]
+fn test_oldest_version() {
+    let temp_dir = tempdir().unwrap();
+    let registry_path = temp_dir.path().join("registry");
+    local_registry(
+        &registry_path,
+        "foo",
+        &[("0.1.0", true), ("0.2.0", false), ("0.3.0", false)],
+    );
+    let vendor_path = temp_dir.path().join("vendor");
+    directory_source(&vendor_path, "foo", &["0.1.0", "0.2.0", "0.3.0"]);
+
+    let oldest = |source: ClonerSource, version: &str, dir: &str| {
+        let cloner = ClonerBuilder::new()
+            .with_source(source)
+            .with_version_selection(VersionSelection::Oldest)
+            .with_directory(temp_dir.path().join(dir))
+            .build()
+            .unwrap();
+        let crate_ = cargo_clone_core::Crate::new(String::from("foo"), Some(String::from(version)));
+        cloner.clone_in_dir(&crate_).unwrap().version.to_string()
+    };
+
+    let vendor = || ClonerSource::directory(vendor_path.to_str().unwrap());
+    assert_eq!("0.1.0", oldest(vendor(), "*", "vendor-any"));
+    assert_eq!("0.2.0", oldest(vendor(), ">=0.2", "vendor-min"));
+    // Yanked versions are never selected.
+    let registry = ClonerSource::local_registry(registry_path.to_str().unwrap());
+    assert_eq!("0.2.0", oldest(registry, "*", "registry-any"));
+
+    assert!(
+        ClonerBuilder::new()
+            .with_version_selection(VersionSelection::Oldest)
+            .with_all_matching(true)
+            .build()
+            .is_err()
+    );
+}
+
+#[test]
+fn test_dir_template_and_destination() {
+    let temp_dir = tempdir().unwrap();
+    let vendor_path = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/vendor");
+
+    let crates = [
+        cargo_clone_core::Crate::new(String::from("foo"), None),
+        cargo_clone_core::Crate::new(String::from("foo"), None).with_destination("forks/foo"),
+    ];
+
+    let cloner = ClonerBuilder::new()
+        .with_source(ClonerSource::directory(vendor_path.to_str().unwrap()))
+        .with_dir_template("{name}-v{version}")
+        .with_directory(temp_dir.path())
+        .build()
+        .unwrap();
+
+    let cloned = cloner.clone(&crates).unwrap();
+
+    assert_eq!(cloned[0].path, temp_dir.path().join("foo-v0.1.0"));
+    assert_eq!(cloned[1].path, temp_dir.path().join("forks/foo"));
+    assert!(temp_dir.path().join("forks/foo/Cargo.toml").exists());
+}
+
+#[test]
+fn test_sync_from_directory_source() {
+    let temp_dir = tempdir().unwrap();
+    let vendor_path = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/vendor");
+
+    let manifest = CloneManifest::parse(
+        "[[crate]]\nname = \"foo\"\npath = \"vendor/foo\"\n",
+        temp_dir.path(),
+    )
+    .unwrap();
+
+    let cloner = ClonerBuilder::new()
+        .with_source(ClonerSource::directory(vendor_path.to_str().unwrap()))
+        .build()
+        .unwrap();
+
+    let synced = cloner.sync(&manifest, false).unwrap();
+    assert_eq!(synced[0].status, SyncStatus::Cloned);
+    assert!(temp_dir.path().join("vendor/foo/Cargo.toml").exists());
+
+    let synced = cloner.sync(&manifest, false).unwrap();
+    assert_eq!(synced[0].status, SyncStatus::UpToDate);
+}
+
+#[test]
+fn test_sync_update_keeps_local_changes() {
+    let temp_dir = tempdir().unwrap();
+    let source_path = temp_dir.path().join("source");
+    directory_source(&source_path, "foo", &["0.1.0", "0.2.0"]);
+    let manifest = |version: &str, path: &str, mode: &str| {
+        let contents = format!(
+            "[[crate]]\nname = \"foo\"\nversion = \"{version}\"\npath = \"{path}\"\nmode = \"{mode}\"\n"
+        );
+        CloneManifest::parse(&contents, temp_dir.path()).unwrap()
+    };
+    let cloner = ClonerBuilder::new()
+        .with_source(ClonerSource::directory(source_path.to_str().unwrap()))
+        .build()
+        .unwrap();
+
+    // Copies are compared with the record of their pristine files.
+    let clone_path = temp_dir.path().join("copy");
+    let synced = cloner
+        .sync(&manifest("0.1.0", "copy", "copy"), false)
+        .unwrap();
+    assert_eq!(synced[0].status, SyncStatus::Cloned);
+    assert!(clone_path.join(".cargo-clone.toml").exists());
+
+    let updated = manifest("0.2.0", "copy", "copy");
+    let synced = cloner.sync(&updated, false).unwrap();
+    assert_eq!(
+        synced[0].status,
+        SyncStatus::Drifted("0.1.0".parse().unwrap())
+    );
+
+    fs::write(clone_path.join("src/lib.rs"), "fn local() {}\n").unwrap();
+    fs::write(clone_path.join("notes.txt"), "").unwrap();
+    let err = cloner.sync(&updated, true).unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("has local changes to `notes.txt`, `src/lib.rs`"),
+        "{err}"
+    );
+    assert_eq!(
+        fs::read_to_string(clone_path.join("src/lib.rs")).unwrap(),
+        "fn local() {}\n"
+    );
+
+    fs::write(clone_path.join("src/lib.rs"), "").unwrap();
+    fs::remove_file(clone_path.join("notes.txt")).unwrap();
+    let synced = cloner.sync(&updated, true).unwrap();
+    assert_eq!(
+        synced[0].status,
+        SyncStatus::Updated("0.1.0".parse().unwrap())
+    );
+    let cargo_toml = fs::read_to_string(clone_path.join("Cargo.toml")).unwrap();
+    assert!(cargo_toml.contains("version = \"0.2.0\""));
+
+    // Git repositories must have no uncommitted changes, committed ones stay in the history.
+    let repo_path = temp_dir.path().join("repo");
+    cloner
+        .sync(&manifest("0.1.0", "repo", "git-init"), false)
+        .unwrap();
+    assert_eq!(git(&repo_path, &["status", "--porcelain"]), "");
+
+    let updated = manifest("0.2.0", "repo", "git-init");
+    fs::write(repo_path.join("src/lib.rs"), "fn local() {}\n").unwrap();
+    let err = cloner.sync(&updated, true).unwrap_err();
+    assert!(err.to_string().contains("has uncommitted changes"), "{err}");
+
+    git(
+        &repo_path,
+        &[
+            "-c",
+            "user.name=Test",
+            "-c",
+            "user.email=test@localhost",
+            "commit",
+            "--quiet",
+            "--all",
+            "--message",
+            "Local change",
+        ],
+    );
+    let synced = cloner.sync(&updated, true).unwrap();
+    assert_eq!(
+        synced[0].status,
+        SyncStatus::Updated("0.1.0".parse().unwrap())
+    );
+    assert_eq!(
+        git(&repo_path, &["rev-list", "--count", "HEAD"]).trim(),
+        "3"
+    );
+    assert_eq!(git(&repo_path, &["status", "--porcelain"]), "");
+}
+
+#[test]
+fn test_sync_update_keeps_other_directories() {
+    let temp_dir = tempdir().unwrap();
+    let source_path = temp_dir.path().join("source");
+    directory_source(&source_path, "foo", &["0.1.0", "0.2.0"]);
+    directory_source(&source_path, "bar", &["0.1.0"]);
+    let clones = |name: &str, path: &str| {
+        let contents =
+            format!("[[crate]]\nname = \"{name}\"\npath = \"{path}\"\nmode = \"git-init\"\n");
+        CloneManifest::parse(&contents, temp_dir.path()).unwrap()
+    };
+    let cloner = ClonerBuilder::new()
+        .with_source(ClonerSource::directory(source_path.to_str().unwrap()))
+        .build()
+        .unwrap();
+
+    // A clean git repository which was not cloned by `sync` has no record.
+    let work_path = temp_dir.path().join("work");
+    fs::create_dir_all(work_path.join("src")).unwrap();
+    fs::write(work_path.join("Cargo.toml"), manifest("foo", "0.1.0")).unwrap();
+    fs::write(work_path.join("src/main.rs"), "fn main() {}\n").unwrap();
+    git(&work_path, &["init", "--quiet"]);
+    git(&work_path, &["add", "--all"]);
+    git(
+        &work_path,
+        &[
+            "-c",
+            "user.name=Test",
+            "-c",
+            "user.email=test@localhost",
+            "commit",
+            "--quiet",
+            "--message",
+            "Work",
+        ],
+    );
+    let err = cloner.sync(&clones("foo", "work"), true).unwrap_err();
+    assert!(err.to_string().contains("has no record"), "{err}");
+    assert!(work_path.join("src/main.rs").exists());
+
+    // The clone of another package is not replaced.
+    let bar_path = temp_dir.path().join("bar");
+    cloner.sync(&clones("bar", "bar"), false).unwrap();
+    let err = cloner.sync(&clones("foo", "bar"), true).unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("is not a clone of foo, it has `bar`"),
+        "{err}"
+    );
+    assert!(
+        fs::read_to_string(bar_path.join("Cargo.toml"))
+            .unwrap()
+            .contains("name = \"bar\"")
+    );
+}
+
+#[test]
+fn test_diff_local_uses_record() {
+    let temp_dir = tempdir().unwrap();
+    let registry_path = temp_dir.path().join("registry");
+    local_registry(&registry_path, "foo", &[("0.1.0", false), ("0.2.0", false)]);
+    let manifest = CloneManifest::parse(
+        "[[crate]]\nname = \"foo\"\nversion = \"0.1.0\"\n",
+        temp_dir.path(),
+    )
+    .unwrap();
+    ClonerBuilder::new()
+        .with_source(ClonerSource::local_registry(
+            registry_path.to_str().unwrap(),
+        ))
+        .build()
+        .unwrap()
+        .sync(&manifest, false)
+        .unwrap();
+    let clone_path = temp_dir.path().join("foo");
+    fs::write(clone_path.join("src/lib.rs"), "fn local() {}\n").unwrap();
+
+    // The cloner's source does not have the package, the recorded one does.
+    let empty_path = temp_dir.path().join("empty");
+    fs::create_dir(&empty_path).unwrap();
+    let diff = ClonerBuilder::new()
+        .with_source(ClonerSource::directory(empty_path.to_str().unwrap()))
+        .build()
+        .unwrap()
+        .diff_local(None, &clone_path)
+        .unwrap();
+
+    assert_eq!(diff.version, "0.1.0".parse().unwrap());
+    let paths = diff
+        .files
+        .iter()
+        .map(|f| f.path.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(paths, ["src/lib.rs"]);
+}
+
+#[test]
+fn test_locked_from_local_registry() {
+    let temp_dir = tempdir().unwrap();
+    let registry_path = temp_dir.path().join("registry");
+    local_registry(&registry_path, "foo", &[("0.1.0", false), ("0.2.0", false)]);
+    let source = || ClonerSource::local_registry(registry_path.to_str().unwrap());
+    let crate_ = cargo_clone_core::Crate::new(String::from("foo"), None);
+    let locked_clone = |lock: &str, dir: &str| {
+        ClonerBuilder::new()
+            .with_source(source())
+            .with_lock(CloneLock::parse(lock).unwrap())
+            .with_directory(temp_dir.path().join(dir))
+            .build()
+            .unwrap()
+            .clone(std::slice::from_ref(&crate_))
+    };
+
+    let cloner = ClonerBuilder::new()
+        .with_source(source())
+        .with_directory(temp_dir.path().join("unlocked"))
+        .build()
+        .unwrap();
+    let cloned = cloner.clone(std::slice::from_ref(&crate_)).unwrap();
+    let mut lock = CloneLock::new();
+    lock.insert(&crate_, &cloned[0]).unwrap();
+    let lock = lock.to_string();
+
+    let locked = locked_clone(&lock, "locked").unwrap();
+    assert_eq!(cloned[0].version, locked[0].version);
+
+    let checksum = cloned[0].checksum.as_deref().unwrap();
+    let changed = lock.replace(checksum, &"0".repeat(64));
+    let err = locked_clone(&changed, "changed").unwrap_err();
+    assert!(err.to_string().contains("does not match the lock"), "{err}");
+
+    // Sources without checksums cannot be locked.
+    let vendor_path = temp_dir.path().join("vendor");
+    directory_source(&vendor_path, "foo", &["0.1.0"]);
+    let cloner = ClonerBuilder::new()
+        .with_source(ClonerSource::directory(vendor_path.to_str().unwrap()))
+        .with_directory(temp_dir.path().join("vendored"))
+        .build()
+        .unwrap();
+    let cloned = cloner.clone(std::slice::from_ref(&crate_)).unwrap();
+    let err = CloneLock::new().insert(&crate_, &cloned[0]).unwrap_err();
+    assert!(
+        err.to_string().contains("does not record checksums"),
+        "{err}"
+    );
+}
+
+#[test]
+fn test_pinned_checksum() {
+    let temp_dir = tempdir().unwrap();
+    let registry_path = temp_dir.path().join("registry");
+    local_registry(&registry_path, "foo", &[("0.1.0", false)]);
+    let checksum = Sha256::new()
+        .update(&crate_archive("foo", "0.1.0"))
+        .finish_hex();
+    let other = "0".repeat(64);
+    let out_path = temp_dir.path().join("out");
+    let clone = |source: ClonerSource, checksum: &str, dir: &str| {
+        let crate_ = cargo_clone_core::Crate::new(String::from("foo"), None)
+            .with_checksum(checksum)
+            .with_destination(dir);
+        ClonerBuilder::new()
+            .with_source(source)
+            .with_directory(&out_path)
+            .build()
+            .unwrap()
+            .clone(&[crate_])
+    };
+    let registry = || ClonerSource::local_registry(registry_path.to_str().unwrap());
+
+    clone(registry(), &checksum, "registry").unwrap();
+    assert!(out_path.join("registry/Cargo.toml").exists());
+
+    // The checksum recorded by the index differs from the pinned one.
+    let err = clone(registry(), &other, "registry-index").unwrap_err();
+    assert!(format!("{err:#}").contains("Checksum mismatch"), "{err:#}");
+    assert!(!out_path.join("registry-index/Cargo.toml").exists());
+
+    // The checksum recorded by a directory source, in `.cargo-checksum.json`, differs from the
+    // pinned one.
+    let vendor_path = temp_dir.path().join("vendor");
+    directory_source(&vendor_path, "foo", &["0.1.0"]);
+    fs::write(
+        vendor_path.join("foo-0.1.0/.cargo-checksum.json"),
+        format!(r#"{{"files":{{}},"package":"{checksum}"}}"#),
+    )
+    .unwrap();
+    let vendor = || ClonerSource::directory(vendor_path.to_str().unwrap());
+    clone(vendor(), &checksum, "vendor").unwrap();
+    let err = clone(vendor(), &other, "vendor-index").unwrap_err();
+    assert!(format!("{err:#}").contains("Checksum mismatch"), "{err:#}");
+    assert!(!out_path.join("vendor-index/Cargo.toml").exists());
+
+    // The archive differs from the checksum recorded by the index.
+    let tampered_path = temp_dir.path().join("tampered");
+    local_registry(&tampered_path, "foo", &[("0.1.0", false)]);
+    fs::write(
+        tampered_path.join("foo-0.1.0.crate"),
+        crate_archive("foo", "0.1.0-tampered"),
+    )
+    .unwrap();
+    let tampered = ClonerSource::local_registry(tampered_path.to_str().unwrap());
+    let err = clone(tampered, &checksum, "tampered").unwrap_err();
+    assert!(format!("{err:#}").contains("checksum"), "{err:#}");
+    assert!(!out_path.join("tampered/Cargo.toml").exists());
+
+    // Dry runs check pinned checksums the same way, before downloading anything.
+    let plan = |checksum: &str, all_matching: bool| {
+        let crate_ =
+            cargo_clone_core::Crate::new(String::from("foo"), None).with_checksum(checksum);
+        ClonerBuilder::new()
+            .with_source(registry())
+            .with_all_matching(all_matching)
+            .with_directory(out_path.join("planned"))
+            .build()
+            .unwrap()
+            .plan(&[crate_])
+    };
+    assert_eq!(
+        plan(&checksum, false).unwrap()[0].checksum,
+        Some(checksum.clone())
+    );
+    let err = plan(&other, false).unwrap_err();
+    assert!(format!("{err:#}").contains("Checksum mismatch"), "{err:#}");
+    let err = plan(&checksum, true).unwrap_err();
+    assert!(err.to_string().contains("can only be pinned"), "{err}");
+
+    // Git repositories are not cloned from the archive.
+    let crate_ = cargo_clone_core::Crate::new(String::from("foo"), None).with_checksum(&checksum);
+    let err = ClonerBuilder::new()
+        .with_source(registry())
+        .with_git(true)
+        .with_directory(out_path.join("git"))
+        .build()
+        .unwrap()
+        .clone(&[crate_])
+        .unwrap_err();
+    assert!(err.to_string().contains("Cannot pin the checksum"), "{err}");
+}
+
+#[test]
+fn test_pinned_checksum_ignores_extracted_sources() {
+    let temp_dir = tempdir().unwrap();
+    let home = temp_dir.path().join("home");
+    let index = git_registry(&temp_dir.path().join("registry"), &[("foo", &["0.1.0"])]);
+    let checksum = Sha256::new()
+        .update(&crate_archive("foo", "0.1.0"))
+        .finish_hex();
+    let out_path = temp_dir.path().join("out");
+    let clone = |checksum: Option<&str>, dir: &str| {
+        let mut crate_ =
+            cargo_clone_core::Crate::new(String::from("foo"), None).with_destination(dir);
+        if let Some(checksum) = checksum {
+            crate_ = crate_.with_checksum(checksum);
+        }
+        ClonerBuilder::new()
+            .with_context(cargo_context(&home, false))
+            .with_source(ClonerSource::index(&index).unwrap())
+            .with_directory(&out_path)
+            .build()
+            .unwrap()
+            .clone(&[crate_])
+            .unwrap();
+        fs::read_to_string(out_path.join(dir).join("src/lib.rs")).unwrap()
+    };
+
+    // The first clone extracts the archive into cargo's cache, where it is changed.
+    assert_eq!(clone(None, "first"), "");
+    let extracted = extracted_dir(&home, &index, "foo", "0.1.0");
+    fs::write(extracted.join("src/lib.rs"), "fn tampered() {}\n").unwrap();
+    assert_eq!(clone(None, "extracted"), "fn tampered() {}\n");
+
+    // With a pinned checksum, the verified archive is unpacked instead.
+    assert_eq!(clone(Some(&checksum), "pinned"), "");
+    let crate_ = cargo_clone_core::Crate::new(String::from("foo"), None).with_checksum(&checksum);
+    let files = ClonerBuilder::new()
+        .with_context(cargo_context(&home, false))
+        .with_source(ClonerSource::index(&index).unwrap())
+        .build()
+        .unwrap()
+        .files(&crate_)
+        .unwrap();
+    let mut contents = String::new();
+    files
+        .open("src/lib.rs")
+        .unwrap()
+        .read_to_string(&mut contents)
+        .unwrap();
+    assert_eq!(contents, "");
+}
+
+#[test]
+fn test_clone_from_extracted_sources() {
+    let temp_dir = tempdir().unwrap();
+    let home = temp_dir.path().join("home");
+    let registry_path = temp_dir.path().join("registry");
+    let index = git_registry(&registry_path, &[("foo", &["0.1.0"])]);
+    // The archive cannot be downloaded, so it can only be cloned from cargo's cache.
+    fs::remove_file(registry_path.join("dl/foo-0.1.0.crate")).unwrap();
+    let extracted = extracted_dir(&home, &index, "foo", "0.1.0");
+    fs::create_dir_all(extracted.join("src")).unwrap();
+    fs::write(extracted.join("Cargo.toml"), manifest("foo", "0.1.0")).unwrap();
+    fs::write(extracted.join("src/lib.rs"), "fn extracted() {}\n").unwrap();
+
+    let out_path = temp_dir.path().join("out");
+    let clone = |link: bool, dir: &str| {
+        let crate_ = cargo_clone_core::Crate::new(String::from("foo"), None).with_destination(dir);
+        ClonerBuilder::new()
+            .with_context(cargo_context(&home, false))
+            .with_source(ClonerSource::index(&index).unwrap())
+            .with_directory(&out_path)
+            .with_link(link)
+            .build()
+            .unwrap()
+            .clone(&[crate_])
+    };
+
+    // Sources are only reused once cargo has marked them as completely extracted.
+    assert!(clone(false, "partial").is_err());
+    fs::write(extracted.join(".cargo-ok"), r#"{"v":1}"#).unwrap();
+
+    clone(false, "copy").unwrap();
+    let copied = out_path.join("copy/src/lib.rs");
+    assert_eq!(fs::read_to_string(&copied).unwrap(), "fn extracted() {}\n");
+    assert!(!out_path.join("copy/.cargo-ok").exists());
+
+    clone(true, "link").unwrap();
+    let linked = out_path.join("link/src/lib.rs");
+    assert_eq!(fs::read_to_string(&linked).unwrap(), "fn extracted() {}\n");
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        let original = fs::metadata(extracted.join("src/lib.rs")).unwrap();
+        assert_eq!(fs::metadata(&linked).unwrap().ino(), original.ino());
+        assert_eq!(original.nlink(), 2);
+        assert_eq!(fs::metadata(&copied).unwrap().nlink(), 1);
+    }
+}
+
+#[test]
+fn test_offline_reports_missing_packages() {
+    let temp_dir = tempdir().unwrap();
+    let home = temp_dir.path().join("home");
+    let index = git_registry(
+        &temp_dir.path().join("registry"),
+        &[("foo", &["0.1.0", "0.2.0"]), ("bar", &["0.1.0"])],
+    );
+    let clone = |offline: bool, crates: &[(&str, &str)]| {
+        let crates = crates
+            .iter()
+            .map(|(name, version)| {
+                cargo_clone_core::Crate::new(name.to_string(), Some(version.to_string()))
+                    .with_destination(format!("{name}-{version}"))
+            })
+            .collect::<Vec<_>>();
+        ClonerBuilder::new()
+            .with_context(cargo_context(&home, offline))
+            .with_source(ClonerSource::index(&index).unwrap())
+            .with_directory(
+                temp_dir
+                    .path()
+                    .join(if offline { "offline" } else { "online" }),
+            )
+            .build()
+            .unwrap()
+            .clone(&crates)
+    };
+
+    // Fetch the index and download foo 0.1.0.
+    clone(false, &[("foo", "0.1.0")]).unwrap();
+
+    let err = clone(
+        true,
+        &[("foo", "0.1.0"), ("bar", "0.1.0"), ("foo", "0.2.0")],
+    )
+    .unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "Unable to clone without network access, some packages are missing from the local cache:
+  - Package `bar@0.1.0` has not been downloaded yet (missing versions: 0.1.0)
+  - Package `foo@0.2.0` has not been downloaded yet (missing versions: 0.2.0)"
+    );
+    // Nothing is cloned until every package is available.
+    assert!(!temp_dir.path().join("offline").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_bisect_skips_unused_patch() {
+    let temp_dir = tempdir().unwrap();
+    let index = git_registry(
+        &temp_dir.path().join("registry"),
+        &[("foo", &["0.1.0", "0.2.0"])],
+    );
+    // The project requires foo 0.2, so a patch with foo 0.1.0 is not used.
+    let project = temp_dir.path().join("project");
+    fs::create_dir_all(project.join(".cargo")).unwrap();
+    fs::create_dir_all(project.join("src")).unwrap();
+    fs::write(
+        project.join(".cargo/config.toml"),
+        format!("[registries.test]\nindex = \"{index}\"\n"),
+    )
+    .unwrap();
+    fs::write(
+        project.join("Cargo.toml"),
+        format!(
+            "{}\n[dependencies]\nfoo = {{ version = \"0.2\", registry = \"test\" }}\n",
+            manifest("app", "0.1.0")
+        ),
+    )
+    .unwrap();
+    fs::write(project.join("src/lib.rs"), "").unwrap();
+
+    let crate_ = cargo_clone_core::Crate::new(String::from("foo"), Some(String::from(">=0.1")));
+    let command = BisectCommand::new(&[String::from("false")])
+        .unwrap()
+        .with_project(&project);
+    let bisection = ClonerBuilder::new()
+        .with_context(cargo_context(&temp_dir.path().join("home"), false))
+        .with_source(ClonerSource::index(&index).unwrap())
+        .build()
+        .unwrap()
+        .bisect(&crate_, |package| command.run(package))
+        .unwrap();
+
+    assert_eq!(
+        bisection,
+        Bisection {
+            last_good: None,
+            first_bad: Some("0.2.0".parse().unwrap()),
+            skipped: vec!["0.1.0".parse().unwrap()],
+        }
+    );
+    assert!(
+        !fs::read_to_string(project.join("Cargo.toml"))
+            .unwrap()
+            .contains("patch")
+    );
+}
+
+#[test]
+fn test_plan_from_directory_source() {
+    let temp_dir = tempdir().unwrap();
+    let vendor_path = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/vendor");
+
+    let crates = [
+        cargo_clone_core::Crate::new(String::from("foo"), None),
+        cargo_clone_core::Crate::new(String::from("foo"), None).with_destination("bar"),
+    ];
+
+    let cloner = ClonerBuilder::new()
+        .with_source(ClonerSource::directory(vendor_path.to_str().unwrap()))
+        .with_directory(temp_dir.path())
+        .build()
+        .unwrap();
+
+    let planned = cloner.plan(&crates).unwrap();
+    assert_eq!(planned[0].version.to_string(), "0.1.0");
+    assert_eq!(planned[1].path, temp_dir.path().join("bar"));
+    assert!(!temp_dir.path().join("foo").exists());
+
+    let err = cloner
+        .plan(&[crates[0].clone(), crates[0].clone()])
+        .unwrap_err();
+    assert!(err.to_string().contains("would both be cloned into"));
+}
+
+#[test]
+fn test_archive_from_directory_source() {
+    let temp_dir = tempdir().unwrap();
+    let vendor_path = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/vendor");
+    let crate_ = cargo_clone_core::Crate::new(String::from("foo"), None);
+    let cloner = |format| {
+        ClonerBuilder::new()
+            .with_source(ClonerSource::directory(vendor_path.to_str().unwrap()))
+            .with_archive(format)
+            .with_directory(temp_dir.path())
+            .build()
+            .unwrap()
+    };
+
+    let cloned = cloner(ArchiveFormat::TarGz)
+        .clone(std::slice::from_ref(&crate_))
+        .unwrap();
+    assert_eq!(cloned[0].path, temp_dir.path().join("foo.tar.gz"));
+    assert!(cloned[0].path.is_file());
+    assert!(!temp_dir.path().join("foo").exists());
+
+    let err = cloner(ArchiveFormat::Crate).clone(&[crate_]).unwrap_err();
+    assert!(err.to_string().contains("does not serve archives"));
+}
+
+#[test]
+fn test_files_from_directory_source() {
+    let vendor_path = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/vendor");
+    let crate_ = cargo_clone_core::Crate::new(String::from("foo"), None);
+
+    let cloner = ClonerBuilder::new()
+        .with_source(ClonerSource::directory(vendor_path.to_str().unwrap()))
+        .build()
+        .unwrap();
+
+    let files = cloner.files(&crate_).unwrap();
+    assert_eq!(files.version().to_string(), "0.1.0");
+    assert!(files.files().contains(&"src/lib.rs".to_string()));
+    assert!(!files.files().contains(&".cargo-checksum.json".to_string()));
+    let mut contents = vec![];
+    files
+        .open("Cargo.toml")
+        .unwrap()
+        .read_to_end(&mut contents)
+        .unwrap();
+    assert_eq!(
+        contents,
+        std::fs::read(vendor_path.join("foo/Cargo.toml")).unwrap()
+    );
+}
+
+#[test]
+fn test_require_source_replacement() {
+    let temp_dir = tempdir().unwrap();
+    let output_path = temp_dir.path().join("foo");
+    let vendor_path = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("tests/vendor");
+
+    let crate_ = cargo_clone_core::Crate::new(String::from("foo"), None);
+
+    let cloner = ClonerBuilder::new()
+        .with_source(ClonerSource::directory(vendor_path.to_str().unwrap()))
+        .with_source_replacement(SourceReplacement::Require)
+        .with_directory(&output_path)
+        .build()
+        .unwrap();
+
+    let err = cloner.clone_in_dir(&crate_).unwrap_err();
+
+    assert!(err.to_string().starts_with("Source replacement is required"));
+    assert!(!output_path.exists());
+}
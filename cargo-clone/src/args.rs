@@ -17,12 +17,21 @@ pub struct CloneOpt {
     /// Terminal coloring.
     #[clap(long, value_enum, value_name = "COLORING")]
     pub color: Option<Color>,
+    /// Output format of the report of cloned packages.
+    #[clap(long, value_enum, value_name = "FMT", default_value_t = MessageFormat::Human)]
+    pub message_format: MessageFormat,
     /// Use verbose output.
     #[clap(short)]
     pub verbose: bool,
     /// Print less output to stdout.
     #[clap(short)]
     pub quiet: bool,
+    /// Run without accessing the network, using only the local cargo cache.
+    #[clap(long)]
+    pub offline: bool,
+    /// Equivalent to `--offline`, following cargo's flag of the same name.
+    #[clap(long)]
+    pub frozen: bool,
     /// A registry name from Cargo config to clone the specified crate from.
     #[clap(long, conflicts_with("index"), value_name = "REGISTRY")]
     pub registry: Option<String>,
@@ -37,15 +46,259 @@ pub struct CloneOpt {
         value_name = "PATH"
     )]
     pub local_registry: Option<String>,
+    /// A directory source (e.g. created by `cargo vendor`) to clone the specified crate from.
+    #[clap(
+        long,
+        conflicts_with("index"),
+        conflicts_with("registry"),
+        conflicts_with("local_registry"),
+        value_name = "PATH"
+    )]
+    pub vendor_dir: Option<String>,
+    /// A `.crate` archive to clone the specified crate from. Can be a path or a URL.
+    #[clap(
+        long,
+        conflicts_with("index"),
+        conflicts_with("registry"),
+        conflicts_with("local_registry"),
+        conflicts_with("vendor_dir"),
+        value_name = "PATH|URL"
+    )]
+    pub crate_file: Option<String>,
+    /// Expected SHA-256 checksum of the archive given to `--crate-file`.
+    /// It verifies the source itself, before reading any crate from it, while `--checksum`
+    /// verifies the archive of a crate selected from a registry, as recorded by the registry.
+    #[clap(long, requires("crate_file"), value_name = "HASH")]
+    pub sha256: Option<String>,
+    /// Expected SHA-256 checksum of the `.crate` archive of the crate, which must be the only one.
+    /// Checksums can also be pinned in crate specs, e.g. 'foo@1.2.3#sha256=…'.
+    #[clap(
+        long,
+        value_name = "HASH",
+        conflicts_with("crate_file"),
+        conflicts_with("from_file"),
+        conflicts_with("sync"),
+        conflicts_with("git")
+    )]
+    pub checksum: Option<String>,
+    /// Whether to follow source replacement from cargo's configuration.
+    #[clap(long, value_enum, value_name = "MODE", default_value_t = SourceReplacement::Allow)]
+    pub source_replacement: SourceReplacement,
     /// Clone from a repository specified in package's metadata.
     #[clap(long)]
     pub git: bool,
+    /// Clone every version matching the requested version into a git repository,
+    /// with one commit per version, tagged `v{version}`.
+    /// Example: 'cargo clone --history foo@>=1.0.0'.
+    #[clap(long, conflicts_with("git"), conflicts_with("compare"))]
+    pub history: bool,
+    /// Select the oldest version matching the requested version, instead of the newest one.
+    #[clap(
+        long,
+        conflicts_with("history"),
+        conflicts_with("all_matching"),
+        conflicts_with("bisect")
+    )]
+    pub min: bool,
+    /// Clone every version matching the requested version into `name-version` subdirectories.
+    /// Example: 'cargo clone --all-matching foo@^1.2'.
+    #[clap(
+        long,
+        conflicts_with("git"),
+        conflicts_with("history"),
+        conflicts_with("compare"),
+        conflicts_with("bisect")
+    )]
+    pub all_matching: bool,
+    /// Name the directories of the crates after this template, using the `{name}` and `{version}`
+    /// placeholders, instead of the crate name. Example: '--dir-template {name}-{version}'.
+    #[clap(long, value_name = "TEMPLATE")]
+    pub dir_template: Option<String>,
+    /// Write each package as a single archive instead of a directory, at its destination
+    /// with the extension of the format appended. `crate` copies the pristine `.crate` archive.
+    #[clap(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        conflicts_with("git"),
+        conflicts_with("history"),
+        conflicts_with("git_init"),
+        conflicts_with("compare"),
+        conflicts_with("bisect"),
+        conflicts_with("sync")
+    )]
+    pub archive: Option<ArchiveFormat>,
+    /// Initialize a git repository in the destination and commit the pristine sources.
+    #[clap(
+        long,
+        conflicts_with("git"),
+        conflicts_with("compare"),
+        conflicts_with("link")
+    )]
+    pub git_init: bool,
+    /// Hard link files from cargo's cache instead of copying them, where possible.
+    #[clap(long, conflicts_with("git"))]
+    pub link: bool,
+    /// Copy the targets of symbolic links instead of recreating the links.
+    #[clap(long, conflicts_with("git"))]
+    pub follow_symlinks: bool,
+    /// Skip files which cannot be read, with a warning, instead of failing.
+    #[clap(long, conflicts_with("git"))]
+    pub skip_unreadable: bool,
+    /// Only clone files matching this glob pattern. Can be specified multiple times.
+    /// Patterns matching a directory select everything inside it.
+    #[clap(long, value_name = "GLOB", conflicts_with("git"))]
+    pub include: Vec<String>,
+    /// Do not clone files matching this glob pattern. Can be specified multiple times.
+    #[clap(long, value_name = "GLOB", conflicts_with("git"))]
+    pub exclude: Vec<String>,
+    /// Use a predefined set of file filters.
+    #[clap(long, value_enum, value_name = "PRESET", conflicts_with("git"))]
+    pub preset: Option<FilterPreset>,
+    /// Only clone this directory or file of the package, relative to its root, into the
+    /// destination. Example: '--subpath src/codec'.
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with("git"),
+        conflicts_with("compare"),
+        conflicts_with("bisect"),
+        conflicts_with("sync")
+    )]
+    pub subpath: Option<String>,
+    /// Print the changes between two versions of a package instead of cloning them.
+    /// Example: 'cargo clone --diff foo@1.2.0 foo@1.3.0'.
+    #[clap(
+        long,
+        group = "compare",
+        conflicts_with("git"),
+        conflicts_with("trailing")
+    )]
+    pub diff: bool,
+    /// Print the changes made in a local directory, e.g. a modified clone, to the published package.
+    /// The package is read from the directory's manifest, unless it is specified.
+    #[clap(
+        long,
+        group = "compare",
+        value_name = "PATH",
+        conflicts_with("git"),
+        conflicts_with("trailing")
+    )]
+    pub diff_dir: Option<String>,
+    /// Only print a summary of the changed files.
+    #[clap(long, requires("compare"))]
+    pub stat: bool,
+    /// Print the changes made in the local directory as a patch in the format of `git format-patch`.
+    #[clap(long, requires("diff_dir"), conflicts_with("stat"))]
+    pub format_patch: bool,
+    /// Find the first version with a regression, by binary search over the versions matching
+    /// the requested version. Each version is cloned and tested with the command after `--`,
+    /// exiting with 0 if it is good, 125 if it cannot be tested and 1 to 127 if it is bad.
+    /// Example: 'cargo clone --bisect foo@>=1.0,<2.0 -- cargo test'.
+    #[clap(
+        long,
+        conflicts_with("git"),
+        conflicts_with("compare"),
+        conflicts_with("history"),
+        conflicts_with("git_init")
+    )]
+    pub bisect: bool,
+    /// Run the bisect command in this cargo project instead of the cloned version,
+    /// with a temporary `[patch]` pointing to the cloned version.
+    #[clap(long, requires("bisect"), value_name = "PATH")]
+    pub bisect_in: Option<String>,
+    /// Print files of a package to stdout instead of cloning it.
+    /// The crate is followed by the paths of the files, relative to the package root.
+    /// Example: 'cargo clone --cat tokio@1.38 src/runtime/mod.rs'.
+    #[clap(
+        long,
+        group = "read",
+        conflicts_with("git"),
+        conflicts_with("history"),
+        conflicts_with("all_matching"),
+        conflicts_with("git_init"),
+        conflicts_with("archive"),
+        conflicts_with("compare"),
+        conflicts_with("bisect"),
+        conflicts_with("from_file"),
+        conflicts_with("trailing")
+    )]
+    pub cat: bool,
+    /// Print the paths of the files of a package instead of cloning it.
+    #[clap(
+        long,
+        group = "read",
+        conflicts_with("git"),
+        conflicts_with("history"),
+        conflicts_with("all_matching"),
+        conflicts_with("git_init"),
+        conflicts_with("archive"),
+        conflicts_with("compare"),
+        conflicts_with("bisect"),
+        conflicts_with("from_file"),
+        conflicts_with("trailing")
+    )]
+    pub list_files: bool,
+    /// Resolve the crates and check their destinations, and print what would be cloned
+    /// without cloning anything.
+    #[clap(
+        long,
+        conflicts_with("compare"),
+        conflicts_with("bisect"),
+        conflicts_with("read"),
+        conflicts_with("sync")
+    )]
+    pub dry_run: bool,
+    /// Record the version and checksum selected for each crate in this lock file, keeping the
+    /// other crates it records.
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with("history"),
+        conflicts_with("all_matching"),
+        conflicts_with("compare"),
+        conflicts_with("bisect"),
+        conflicts_with("read"),
+        conflicts_with("sync")
+    )]
+    pub lockfile: Option<String>,
+    /// Clone the versions recorded in the lock file, and fail if their checksum changed or if
+    /// their archive does not match it.
+    #[clap(long, requires("lockfile"))]
+    pub locked: bool,
+    /// Clone the crates listed in a `clones.toml` file which are missing, and report the clones
+    /// which have another version than the selected one.
+    /// Example: 'cargo clone --sync clones.toml'.
+    #[clap(
+        long,
+        value_name = "PATH",
+        conflicts_with("crate_"),
+        conflicts_with("trailing"),
+        conflicts_with("git"),
+        conflicts_with("history"),
+        conflicts_with("all_matching"),
+        conflicts_with("compare"),
+        conflicts_with("bisect"),
+        conflicts_with("read")
+    )]
+    pub sync: Option<String>,
+    /// Update the clones which have another version than the selected one while syncing.
+    #[clap(long, requires("sync"))]
+    pub update: bool,
+    /// Read more crates from a file, or from stdin if it is `-`, one per line.
+    /// Lines starting with `#` are comments.
+    #[clap(long, value_name = "PATH", conflicts_with("sync"))]
+    pub from_file: Option<String>,
     /// The crates to be downloaded. Versions may also be specified and are matched exactly by default.
-    /// Examples: 'cargo-clone@1.0.0' 'cargo-clone@~1.0.0'.
+    /// Crates can be prefixed with a registry from Cargo config, or be package ID specs.
+    /// A destination directory can be appended after `=`.
+    /// With `--cat`, the crate is followed by the paths of the files to print.
+    /// Examples: 'cargo-clone@1.0.0' 'cargo-clone@~1.0.0' 'my-registry/foo@1.2.3' 'foo=vendor/foo'.
     pub crate_: Vec<String>,
     /// The destination directory. If it ends in a slash, crates will be placed into its subdirectories.
-    #[clap(last = true)]
-    pub directory: Option<String>,
+    /// With `--bisect`, the command to test each version with.
+    #[clap(last = true, value_name = "DIRECTORY|COMMAND")]
+    pub trailing: Vec<String>,
 }
 
 #[derive(Debug, Clone, Copy, ValueEnum)]
@@ -64,3 +317,76 @@ impl fmt::Display for Color {
         }
     }
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MessageFormat {
+    Human,
+    Json,
+}
+
+impl fmt::Display for MessageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Human => write!(f, "human"),
+            Self::Json => write!(f, "json"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum SourceReplacement {
+    /// Follow the configured source replacement.
+    Allow,
+    /// Clone from the original source.
+    Ignore,
+    /// Fail if the source is not replaced.
+    Require,
+}
+
+impl fmt::Display for SourceReplacement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Allow => write!(f, "allow"),
+            Self::Ignore => write!(f, "ignore"),
+            Self::Require => write!(f, "require"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum FilterPreset {
+    /// Only `Cargo.toml`, `build.rs` and `src/`.
+    SourcesOnly,
+    /// Everything except `tests/`, `benches/` and `examples/`.
+    NoDevFiles,
+}
+
+impl fmt::Display for FilterPreset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::SourcesOnly => write!(f, "sources-only"),
+            Self::NoDevFiles => write!(f, "no-dev-files"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum ArchiveFormat {
+    /// A gzipped tarball.
+    #[value(name = "tar.gz")]
+    TarGz,
+    /// A zip archive.
+    Zip,
+    /// The pristine `.crate` archive.
+    Crate,
+}
+
+impl fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::TarGz => write!(f, "tar.gz"),
+            Self::Zip => write!(f, "zip"),
+            Self::Crate => write!(f, "crate"),
+        }
+    }
+}
@@ -8,10 +8,21 @@
 
 mod args;
 
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+
 use anyhow::Context;
-use args::{CloneOpt, Command};
+use args::{CloneOpt, Command, MessageFormat};
 use cargo::util::context::GlobalContext;
-use cargo_clone_core::{ClonerBuilder, ClonerSource};
+use cargo_clone_core::{
+    ArchiveFormat, BisectCommand, CloneLock, CloneManifest, ClonedPackage, Cloner, ClonerBuilder,
+    ClonerSource, Crate, FilterPreset, PlannedClone, SourceReplacement, SyncStatus, SyncedCrate,
+    VersionSelection,
+};
 use clap::Parser;
 
 type Result<T> = std::result::Result<T, anyhow::Error>;
@@ -36,9 +47,9 @@ fn cargo_context(matches: &CloneOpt) -> Result<GlobalContext> {
         verbose,
         matches.quiet,
         color.as_deref(),
+        matches.frozen,
         false,
-        false,
-        false,
+        matches.offline,
         &None,
         &[],
         &[],
@@ -53,48 +64,469 @@ fn source(opts: &CloneOpt) -> Result<ClonerSource> {
         ClonerSource::index(index)?
     } else if let Some(path) = &opts.local_registry {
         ClonerSource::local_registry(path)
+    } else if let Some(path) = &opts.vendor_dir {
+        ClonerSource::directory(path)
+    } else if let Some(location) = &opts.crate_file {
+        if let Some(sha256) = &opts.sha256
+            && !cargo_clone_core::is_valid_checksum(sha256)
+        {
+            anyhow::bail!("`--sha256` expects a SHA-256 checksum of 64 hexadecimal digits.");
+        }
+        ClonerSource::crate_file(location, opts.sha256.clone())
     } else {
         ClonerSource::crates_io()
     };
     Ok(source)
 }
 
+fn source_replacement(opts: &CloneOpt) -> SourceReplacement {
+    match opts.source_replacement {
+        args::SourceReplacement::Allow => SourceReplacement::Allow,
+        args::SourceReplacement::Ignore => SourceReplacement::Ignore,
+        args::SourceReplacement::Require => SourceReplacement::Require,
+    }
+}
+
+fn filter_preset(preset: args::FilterPreset) -> FilterPreset {
+    match preset {
+        args::FilterPreset::SourcesOnly => FilterPreset::SourcesOnly,
+        args::FilterPreset::NoDevFiles => FilterPreset::NoDevFiles,
+    }
+}
+
+fn archive_format(format: args::ArchiveFormat) -> ArchiveFormat {
+    match format {
+        args::ArchiveFormat::TarGz => ArchiveFormat::TarGz,
+        args::ArchiveFormat::Zip => ArchiveFormat::Zip,
+        args::ArchiveFormat::Crate => ArchiveFormat::Crate,
+    }
+}
+
+fn package_json(package: &ClonedPackage) -> serde_json::Value {
+    serde_json::json!({
+        "name": package.name,
+        "version": package.version.to_string(),
+        "source": package.source_id.as_url().to_string(),
+        "replaced_source": package.replaced_source_id.as_url().to_string(),
+        "checksum": package.checksum,
+        "path": package.path.display().to_string(),
+        "skipped_files": package.skipped_files,
+        "skipped_bytes": package.skipped_bytes,
+    })
+}
+
+fn planned_json(planned: &PlannedClone) -> serde_json::Value {
+    serde_json::json!({
+        "name": planned.name,
+        "version": planned.version.to_string(),
+        "source": planned.source_id.as_url().to_string(),
+        "checksum": planned.checksum,
+        "path": planned.path.display().to_string(),
+        "repository": planned.repository,
+        "commit": planned.commit,
+    })
+}
+
+fn print_plan(planned: &[PlannedClone], opts: &CloneOpt) {
+    for planned in planned {
+        if opts.message_format == MessageFormat::Json {
+            println!("{}", planned_json(planned));
+            continue;
+        }
+
+        let (name, version, path) = (&planned.name, &planned.version, planned.path.display());
+        match (&planned.repository, &planned.commit) {
+            (Some(repository), Some(commit)) => {
+                println!("Would clone {repository} at {commit} into {path} ({name} {version})")
+            }
+            (Some(repository), None) => {
+                println!("Would clone {repository} into {path} ({name} {version})")
+            }
+            _ => println!(
+                "Would clone {name} {version} from {} into {path}",
+                planned.source_id
+            ),
+        }
+    }
+}
+
+fn synced_json(synced: &SyncedCrate) -> serde_json::Value {
+    let (status, previous_version) = match &synced.status {
+        SyncStatus::Cloned => ("cloned", None),
+        SyncStatus::UpToDate => ("up-to-date", None),
+        SyncStatus::Drifted(version) => ("drifted", Some(version.to_string())),
+        SyncStatus::Updated(version) => ("updated", Some(version.to_string())),
+    };
+    serde_json::json!({
+        "name": synced.name,
+        "version": synced.version.to_string(),
+        "path": synced.path.display().to_string(),
+        "status": status,
+        "previous_version": previous_version,
+    })
+}
+
+fn sync(cloner: &Cloner, path: &str, opts: &CloneOpt) -> Result<()> {
+    let manifest = CloneManifest::load(path)?;
+    let synced = cloner
+        .sync(&manifest, opts.update)
+        .context("Error while syncing")?;
+
+    for synced in &synced {
+        if opts.message_format == MessageFormat::Json {
+            println!("{}", synced_json(synced));
+            continue;
+        }
+
+        let (name, version, path) = (&synced.name, &synced.version, synced.path.display());
+        match &synced.status {
+            SyncStatus::Cloned => println!("{name} {version}: cloned into {path}"),
+            SyncStatus::UpToDate => println!("{name} {version}: up to date in {path}"),
+            SyncStatus::Drifted(cloned) => {
+                println!("{name} {version}: {path} has {cloned}, use `--update` to update it")
+            }
+            SyncStatus::Updated(cloned) => {
+                println!("{name} {version}: updated {path} from {cloned}")
+            }
+        }
+    }
+    Ok(())
+}
+
+fn diff(cloner: &Cloner, crates: &[Crate], stat: bool) -> Result<()> {
+    let [old, new] = crates else {
+        anyhow::bail!("`--diff` requires exactly two packages, e.g. `foo@1.2.0 foo@1.3.0`.");
+    };
+    let diff = cloner
+        .diff(old, new)
+        .context("Error while comparing packages")?;
+
+    write_output(|out| {
+        if stat {
+            diff.write_stat(out)
+        } else {
+            diff.write_unified(out)
+        }
+    })
+}
+
+fn diff_local(cloner: &Cloner, crates: &[Crate], dir: &Path, opts: &CloneOpt) -> Result<()> {
+    if crates.len() > 1 {
+        anyhow::bail!("`--diff-dir` accepts at most one package.");
+    }
+    let diff = cloner
+        .diff_local(crates.first(), dir)
+        .context("Error while comparing packages")?;
+
+    if opts.format_patch {
+        let author = git_author()?;
+        let subject = format!("Local changes to {} {}", diff.name, diff.version);
+        write_output(|out| diff.write_patch(out, &author, SystemTime::now(), &subject))
+    } else if opts.stat {
+        write_output(|out| diff.write_stat(out))
+    } else {
+        write_output(|out| diff.write_unified(out))
+    }
+}
+
+fn bisect(cloner: &Cloner, crates: &[Crate], opts: &CloneOpt) -> Result<()> {
+    let [crate_] = crates else {
+        anyhow::bail!("`--bisect` requires exactly one package, e.g. `foo@>=1.0,<2.0`.");
+    };
+    let mut command = BisectCommand::new(&opts.trailing)?;
+    if let Some(project) = &opts.bisect_in {
+        let project = std::path::absolute(project).context("Invalid project path")?;
+        command = command.with_project(project);
+    }
+
+    // Ctrl-C also reaches cargo-clone while the command runs. Instead of exiting, which would
+    // leave the project patched, the bisection stops once the command has exited, so the patch
+    // is removed. A second Ctrl-C exits immediately.
+    static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            process::exit(130);
+        }
+    })
+    .context("Unable to handle Ctrl-C")?;
+    let check_interrupted = || {
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            anyhow::bail!("Interrupted.");
+        }
+        Ok(())
+    };
+
+    let bisection = cloner
+        .bisect(crate_, |package| {
+            check_interrupted()?;
+            let outcome = command.run(package);
+            check_interrupted()?;
+            outcome
+        })
+        .context("Error while bisecting")?;
+
+    let name = crate_.name();
+    let skipped: Vec<String> = bisection.skipped.iter().map(|v| v.to_string()).collect();
+    match (
+        &bisection.last_good,
+        &bisection.first_bad,
+        skipped.as_slice(),
+    ) {
+        (None, None, _) => println!("No version of {name} could be tested."),
+        (_, None, _) => println!("No version of {name} has the regression."),
+        (None, Some(first_bad), []) => println!(
+            "No good version of {name} was found in the range, {first_bad} is the oldest version and is bad."
+        ),
+        (None, Some(first_bad), skipped) => println!(
+            "No good version of {name} was found in the range, {first_bad} is bad and {} could not be tested.",
+            skipped.join(", ")
+        ),
+        (Some(_), Some(first_bad), []) => println!("{name} {first_bad} is the first bad version."),
+        (Some(_), Some(first_bad), skipped) => {
+            println!(
+                "The first bad version of {name} is one of {}, {first_bad}.",
+                skipped.join(", ")
+            );
+        }
+    }
+    Ok(())
+}
+
+fn print_files(cloner: &Cloner, crates: &[Crate], paths: &[String], opts: &CloneOpt) -> Result<()> {
+    let [crate_] = crates else {
+        anyhow::bail!("`--cat` and `--list-files` require exactly one package.");
+    };
+    if opts.cat && paths.is_empty() {
+        anyhow::bail!(
+            "`--cat` requires the paths of the files to print after the package, e.g. `foo@1.2.3 src/lib.rs`."
+        );
+    }
+    let files = cloner
+        .files(crate_)
+        .context("Error while reading the package")?;
+
+    if opts.list_files {
+        return write_output(|out| {
+            files
+                .files()
+                .iter()
+                .try_for_each(|path| writeln!(out, "{path}"))
+        });
+    }
+    // Every file is opened before anything is printed, so nothing is printed if one is missing.
+    let mut opened = paths
+        .iter()
+        .map(|path| files.open(path))
+        .collect::<Result<Vec<_>>>()?;
+    write_output(|out| {
+        opened
+            .iter_mut()
+            .try_for_each(|file| io::copy(file, out).map(|_| ()))
+    })
+}
+
+// read_crate_list reads crate specs from a file, or from stdin if `path` is `-`.
+fn read_crate_list(path: &str) -> Result<Vec<Crate>> {
+    let list = if path == "-" {
+        let mut list = String::new();
+        io::stdin()
+            .read_to_string(&mut list)
+            .context("Unable to read the crates from stdin.")?;
+        list
+    } else {
+        fs::read_to_string(path).with_context(|| format!("Unable to read `{path}`."))?
+    };
+
+    cargo_clone_core::parse_crate_list(&list).with_context(|| {
+        let source = if path == "-" { "stdin" } else { path };
+        format!("Unable to read the crates from `{source}`.")
+    })
+}
+
+// git_author returns the name and email of the user, as configured for git.
+fn git_author() -> Result<String> {
+    let output = process::Command::new("git")
+        .args(["var", "GIT_AUTHOR_IDENT"])
+        .output()
+        .context("Unable to run `git`.")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "Unable to determine the author of the patch, set `user.name` and `user.email` in git's configuration."
+        );
+    }
+
+    // The identity ends with a timestamp and a time zone offset.
+    let ident = String::from_utf8_lossy(&output.stdout);
+    let author = ident.trim().rsplitn(3, ' ').nth(2).unwrap_or_default();
+    Ok(author.to_string())
+}
+
+// write_output writes to stdout, which is usually piped into a pager.
+fn write_output(write: impl FnOnce(&mut dyn Write) -> io::Result<()>) -> Result<()> {
+    let mut out = io::BufWriter::new(io::stdout().lock());
+    let written = write(&mut out).and_then(|()| out.flush());
+
+    match written {
+        // The reader, e.g. a pager, has exited.
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        written => Ok(written?),
+    }
+}
+
 pub fn execute(opts: &CloneOpt) -> Result<()> {
     let source = source(opts).context("invalid source")?;
 
-    let crates = opts
-        .crate_
+    // With `--cat`, the crate is followed by the paths of the files to print.
+    let (specs, paths) = match opts.crate_.split_first() {
+        Some((crate_, paths)) if opts.cat => (std::slice::from_ref(crate_), paths),
+        _ => (opts.crate_.as_slice(), &[][..]),
+    };
+    let mut crates = specs
         .iter()
         .map(|c| c.as_str())
         .map(cargo_clone_core::parse_name_and_version)
-        .collect::<Result<Vec<cargo_clone_core::Crate>>>()?;
+        .collect::<Result<Vec<Crate>>>()?;
+    if let Some(path) = &opts.from_file {
+        crates.extend(read_crate_list(path)?);
+    }
+    if let Some(checksum) = &opts.checksum {
+        if !cargo_clone_core::is_valid_checksum(checksum) {
+            anyhow::bail!("`--checksum` expects a SHA-256 checksum of 64 hexadecimal digits.");
+        }
+        let [crate_] = crates.as_mut_slice() else {
+            anyhow::bail!("`--checksum` requires exactly one crate.");
+        };
+        *crate_ = crate_.clone().with_checksum(checksum);
+    }
 
     let context = cargo_context(opts)?;
     let mut cloner_builder = ClonerBuilder::new()
         .with_source(source)
+        .with_source_replacement(source_replacement(opts))
         .with_context(context);
-    let directory = opts.directory.as_deref();
+    let directory = if opts.bisect {
+        None
+    } else {
+        match opts.trailing.as_slice() {
+            [] => None,
+            [directory] => Some(directory.as_str()),
+            _ => anyhow::bail!("Only one destination directory can be specified."),
+        }
+    };
     if let Some(directory) = directory {
         cloner_builder = cloner_builder.with_directory(directory);
     }
     if opts.git {
         cloner_builder = cloner_builder.with_git(true);
     }
+    if opts.history {
+        cloner_builder = cloner_builder.with_history(true);
+    }
+    if opts.min {
+        cloner_builder = cloner_builder.with_version_selection(VersionSelection::Oldest);
+    }
+    if opts.all_matching {
+        cloner_builder = cloner_builder.with_all_matching(true);
+    }
+    if let (Some(path), true) = (&opts.lockfile, opts.locked) {
+        cloner_builder = cloner_builder.with_lock(CloneLock::load(path)?);
+    }
+    if let Some(format) = opts.archive {
+        cloner_builder = cloner_builder.with_archive(archive_format(format));
+    }
+    if let Some(template) = &opts.dir_template {
+        cloner_builder = cloner_builder.with_dir_template(template);
+    }
+    if opts.git_init {
+        cloner_builder = cloner_builder.with_git_init(true);
+    }
+    if opts.link {
+        cloner_builder = cloner_builder.with_link(true);
+    }
+    if opts.follow_symlinks {
+        cloner_builder = cloner_builder.with_follow_symlinks(true);
+    }
+    if opts.skip_unreadable {
+        cloner_builder = cloner_builder.with_skip_unreadable(true);
+    }
+    if let Some(preset) = opts.preset {
+        cloner_builder = cloner_builder.with_preset(filter_preset(preset));
+    }
+    if let Some(subpath) = &opts.subpath {
+        cloner_builder = cloner_builder.with_subpath(subpath);
+    }
+    cloner_builder = cloner_builder
+        .with_include(&opts.include)
+        .with_exclude(&opts.exclude);
 
     let cloner = cloner_builder
         .build()
         .context("Failed to setup cargo-clone")?;
 
+    if opts.diff {
+        return diff(&cloner, &crates, opts.stat);
+    }
+    if let Some(dir) = &opts.diff_dir {
+        return diff_local(&cloner, &crates, Path::new(dir), opts);
+    }
+    if opts.bisect {
+        return bisect(&cloner, &crates, opts);
+    }
+    if let Some(path) = &opts.sync {
+        return sync(&cloner, path, opts);
+    }
+    if opts.cat || opts.list_files {
+        return print_files(&cloner, &crates, paths, opts);
+    }
+
+    if crates.is_empty() {
+        anyhow::bail!("No crates to clone were specified.");
+    }
+
     let should_append_crate_dir = {
-        let multiple_crates = crates.len() > 1;
+        let multiple_crates = crates.len() > 1 || opts.all_matching;
+        let named_dirs =
+            opts.dir_template.is_some() || crates.iter().any(|c| c.destination().is_some());
         let can_clone_in_dir = directory.map(|d| d.ends_with('/')).unwrap_or(true);
-        multiple_crates || can_clone_in_dir
+        multiple_crates || named_dirs || can_clone_in_dir
     };
 
-    if should_append_crate_dir {
+    if opts.dry_run {
+        let planned = if should_append_crate_dir {
+            cloner.plan(&crates)
+        } else {
+            cloner.plan_in_dir(&crates[0]).map(|planned| vec![planned])
+        }
+        .context("Unable to clone")?;
+        print_plan(&planned, opts);
+        return Ok(());
+    }
+
+    let cloned = if should_append_crate_dir {
         cloner.clone(&crates)
     } else {
-        cloner.clone_in_dir(&crates[0])
+        cloner.clone_in_dir(&crates[0]).map(|package| vec![package])
+    }
+    .context("Error while cloning")?;
+
+    if let (Some(path), false) = (&opts.lockfile, opts.locked) {
+        // Crates which were not cloned this time keep their locked version.
+        let mut lock = if Path::new(path).exists() {
+            CloneLock::load(path)?
+        } else {
+            CloneLock::new()
+        };
+        for (crate_, package) in crates.iter().zip(&cloned) {
+            lock.insert(crate_, package)?;
+        }
+        lock.save(path)?;
     }
-    .context("Error while cloning")
+
+    if opts.message_format == MessageFormat::Json {
+        for package in &cloned {
+            println!("{}", package_json(package));
+        }
+    }
+
+    Ok(())
 }